@@ -0,0 +1,54 @@
+//! Chunking a five-hour podcast, the longest transcripts the bot is asked to read.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+#[path = "../src/chunks.rs"]
+mod chunks;
+
+use chunks::Chunker;
+
+const MODEL: &str = "gpt-4o-mini";
+
+const ENGLISH: &[&str] = &[
+    "so", "the", "book", "I", "was", "reading", "is", "called", "thinking", "fast", "and", "slow", "by",
+    "daniel", "kahneman", "it's", "about", "how", "we", "decide", "really", "good", "chapter", "🙂",
+];
+const RUSSIAN: &[&str] = &[
+    "вот", "книга", "которую", "я", "читал", "называется", "мастер", "и", "маргарита", "булгакова",
+    "она", "про", "то", "как", "мы", "решаем", "очень", "хорошая", "глава", "😄",
+];
+
+/// Five hours at 150 words a minute, seven words a cue, from a fixed pseudo-random word sequence.
+fn transcript(words: &[&str]) -> String {
+    let mut seed: u64 = 42;
+    let mut lines = vec![];
+    for _ in 0..5 * 60 * 150 / 7 {
+        let line: Vec<&str> = (0..7)
+            .map(|_| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                words[(seed >> 33) as usize % words.len()]
+            })
+            .collect();
+        lines.push(line.join(" "));
+    }
+    lines.join("\n")
+}
+
+fn split(c: &mut Criterion) {
+    let chunker = Chunker::from_env(MODEL).expect("tokenizer");
+    let mut group = c.benchmark_group(format!("split_5h_{}_{}", MODEL, chunker.chunk_tokens));
+    group.sample_size(10);
+    for (name, words) in [("english", ENGLISH), ("russian", RUSSIAN)] {
+        let text = transcript(words);
+        group.throughput(Throughput::Bytes(text.len() as u64));
+        group.bench_function(name, |b| b.iter_batched(|| text.as_str(), |text| chunker.split(text), BatchSize::SmallInput));
+        // The same podcast in 30 chapters of ten minutes
+        let lines: Vec<&str> = text.lines().collect();
+        let chapters: Vec<String> = lines.chunks(lines.len() / 30).map(|chapter| chapter.join("\n")).collect();
+        group.bench_function(format!("{}_chapters", name), |b| b.iter(|| chunker.split_sections(&chapters)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, split);
+criterion_main!(benches);
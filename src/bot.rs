@@ -1,20 +1,73 @@
 
 use teloxide::{prelude::*, types::Update};
-use teloxide::types::{Message, UpdateKind};
+use teloxide::types::{CallbackQuery, InlineKeyboardMarkup, Message, ParseMode, User};
+use teloxide::utils::command::BotCommands;
+use teloxide::utils::html;
+use teloxide::stop::{mk_stop_token, StopFlag, StopToken};
+use teloxide::update_listeners::{self, StatefulListener, UpdateListener};
 use teloxide::Bot;
 use regex::Regex;
-use uuid::Uuid;
-use core::str;
-use std::process::Command;
+use futures::{Stream, StreamExt};
 use std::{env, process};
+use std::convert::Infallible;
 use std::error::Error;
+use std::fmt::Debug;
+use std::ops::Range;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
 use warp::Filter;
 use url::Url;
 
 use bytes::Bytes;
 
+mod cache;
+mod cancel;
+mod chunks;
+mod error;
 mod extract_json; 
+mod history;
+mod i18n;
+mod llm;
+mod pipeline;
+mod progress;
+mod queue;
 mod rate_limiter;
+mod settings;
+mod singleflight;
+mod subtitles;
+mod tracks;
+mod ytdlp;
+
+#[derive(BotCommands, Clone)]
+#[command(rename_rule = "lowercase", description = "These commands are supported:")]
+enum Command {
+    #[command(description = "what this bot does")]
+    Start,
+    #[command(description = "show this help")]
+    Help,
+    #[command(description = "show your settings")]
+    Settings,
+    #[command(description = "videos you have sent before")]
+    History,
+    #[command(description = "subtitle language for videos without a known one, e.g. /lang en; /lang none to unset")]
+    Lang(String),
+    #[command(description = "how to list the books: plain or numbered")]
+    Format(String),
+    #[command(hide)]
+    Invalidate(String),
+}
+
+/// Everything needed to turn a link into a list of books, shared by all handlers.
+#[derive(Clone)]
+struct Videos {
+    pipeline: pipeline::Pipeline,
+    queue: queue::JobQueue,
+    jobs: cancel::CancelRegistry,
+    history: history::History,
+    /// Videos with several subtitle tracks, waiting for their user to pick one.
+    picks: tracks::PendingPicks<PendingVideo>,
+}
 
 #[tokio::main]
 async fn main() {
@@ -46,39 +99,95 @@ async fn main() {
         }
     }
     
-    match env::var("OPENAI_TOKEN") {
-        Ok(token) if token.is_empty() => {
-            log::error!("OPENAI_TOKEN env is set but empty");
+    let llm = match llm::from_env() {
+        Ok(llm) => llm,
+        Err(err) => {
+            log::error!("Failed to set up the LLM provider: {}", err);
             process::exit(1);
         }
-        Err(env::VarError::NotPresent) => {
-            log::error!("OPENAI_TOKEN env is not set");
+    };
+    
+    let database_path = env::var("DATABASE_PATH").unwrap_or_else(|_| "./ytparse.sqlite3".to_string());
+    let cache_ttl_hours: u64 = env::var("CACHE_TTL_HOURS").unwrap_or_else(|_| "168".to_string()).parse().expect("Invalid CACHE_TTL_HOURS number");
+    let cache = match cache::ResultCache::open(&database_path, Duration::from_secs(cache_ttl_hours * 3600)) {
+        Ok(cache) => cache,
+        Err(err) => {
+            log::error!("Failed to open result cache at {}: {}", database_path, err);
             process::exit(1);
         }
-        Ok(_) => {}
+    };
+    match cache.purge_expired() {
+        Ok(purged) => log::info!("Purged {} expired cache entries", purged),
+        Err(err) => log::error!("Failed to purge expired cache entries: {}", err),
+    }
+    let settings = match settings::SettingsStore::open(&database_path) {
+        Ok(settings) => settings,
+        Err(err) => {
+            log::error!("Failed to open settings store at {}: {}", database_path, err);
+            process::exit(1);
+        }
+    };
+    let history = match history::History::open(&database_path) {
+        Ok(history) => history,
+        Err(err) => {
+            log::error!("Failed to open history at {}: {}", database_path, err);
+            process::exit(1);
+        }
+    };
+    let ytdlp = ytdlp::YtDlp::new(ytdlp::YtDlpConfig::from_env(), env_usize("YT_DLP_PROCESSES", 4));
+    match ytdlp.version().await {
+        Ok(version) => log::info!("Using yt-dlp {} at {}", version, ytdlp.binary()),
         Err(err) => {
-            log::error!("Failed to read OPENAI_TOKEN env: {}", err);
+            log::error!("yt-dlp at {} is not usable: {}", ytdlp.binary(), err);
             process::exit(1);
         }
     }
-    
+
+    let chunker = match chunks::Chunker::from_env(llm.model()) {
+        Ok(chunker) => chunker,
+        Err(err) => {
+            log::error!("Failed to set up chunking: {}", err);
+            process::exit(1);
+        }
+    };
+    log::info!("Using {} with {} tokens per chunk, {} reserved for answers", llm.model(), chunker.chunk_tokens, chunker.output_reserve);
+
     log::info!("Starting bot...");
     let bot = Bot::from_env();
 
     let environment = env::var("ENVIRONMENT").unwrap_or_else(|_| "development".to_string());
     
-    
+    let production = environment == "production";
+    let rate_limiter = if production {
+        rate_limiter::RateLimiterWrapper::new(100, 1000, 10000) // 100 RPM, 1000 RPD, 10000 TPM
+    } else {
+        rate_limiter::RateLimiterWrapper::new(100, 1000, 200000)
+    };
+    let pipeline = pipeline::Pipeline::new(rate_limiter, cache, ytdlp, tracks::TrackPolicy::from_env(), chunker, llm, env_usize("LLM_CONCURRENCY", 4));
 
-    if environment == "production" {
-        run_webhook(bot, port).await;
+    if production {
+        run_webhook(bot, port, pipeline, settings, history).await;
     } else {
-        run_polling(bot).await;
+        run_polling(bot, pipeline, settings, history).await;
+    }
+}
+
+fn env_usize(name: &str, default: usize) -> usize {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| panic!("Invalid {} number", name)),
+        Err(_) => default,
     }
 }
 
-async fn run_webhook(bot: Bot, port: u16) {
+fn job_queue() -> queue::JobQueue {
+    let workers = env_usize("QUEUE_WORKERS", 2);
+    let max_per_user = env_usize("MAX_JOBS_PER_USER", 2);
+    log::info!("Starting job queue with {} workers, {} jobs per user", workers, max_per_user);
+    queue::JobQueue::new(workers, max_per_user)
+}
+
+async fn run_webhook(bot: Bot, port: u16, pipeline: pipeline::Pipeline, settings: settings::SettingsStore, history: history::History) {
     log::info!("Running in webhook mode...");
-    let rate_limiter = rate_limiter::RateLimiterWrapper::new(100, 1000, 10000); // 100 RPM, 1000 RPD, 10000 TPM
     let webhook_url: Url = env::var("WEBHOOK_URL")
         .expect("WEBHOOK_URL must be set")
         .parse()
@@ -90,139 +199,713 @@ async fn run_webhook(bot: Bot, port: u16) {
 
     log::info!("Starting server on port {}", port);
     
+    let (tx, rx) = mpsc::unbounded_channel::<Update>();
     let webhook_filter = warp::post()
         .and(warp::path::end())
         .and(warp::body::bytes())
         .map(move |body: Bytes| {
-            let bot_clone = bot.clone();
             let update = serde_json::from_slice::<Update>(&body).expect("Failed to parse update");
-            let rl_wrap_clone = rate_limiter.clone(); 
-            tokio::spawn(async move {
-                if let UpdateKind::Message(msg) = update.kind {
-                    process_message(&bot_clone, msg, &rl_wrap_clone).await.map_err(|e| {
-                        log::error!("Failed to process message: {:?}", e);
-                    }).ok(); 
-                }
-                
-            });
+            if tx.send(update).is_err() {
+                log::error!("Update dropped, dispatcher is gone");
+            }
 
             warp::reply::with_status("Webhook received", warp::http::StatusCode::OK)
         });
 
-    warp::serve(webhook_filter).run(([0, 0, 0, 0], port)).await;
-    
+    tokio::spawn(warp::serve(webhook_filter).run(([0, 0, 0, 0], port)));
+    let videos = Videos { pipeline, queue: job_queue(), jobs: cancel::CancelRegistry::new(), history, picks: tracks::PendingPicks::new() };
+    dispatch(bot, videos, settings, webhook_listener(rx)).await;
 }
 
-async fn run_polling(bot: Bot) {
+async fn run_polling(bot: Bot, pipeline: pipeline::Pipeline, settings: settings::SettingsStore, history: history::History) {
     log::info!("Running in polling mode...");
-    let rate_limiter = rate_limiter::RateLimiterWrapper::new(100, 1000, 200000); 
-    let rl_wrap_clone = rate_limiter.clone();
-    teloxide::repl(bot, move |bot_clone: Bot, msg: Message| {
-        let rl_wrap_clone = rl_wrap_clone.clone();
-        async move {
-            process_message(&bot_clone, msg, &rl_wrap_clone).await.map_err(|e| {
-                log::error!("Failed to process message: {:?}", e);
-            }).ok(); 
-            Ok(())
-        }
-    })
-    .await;
-}
-
-async fn extract_lang(url: &str) -> Result<String, Box<dyn Error>> {
-    let output = Command::new("/usr/local/bin/yt-dlp")
-        .arg("--abort-on-error")
-        .arg("--print")
-        .arg("video:language")
-        .arg(url)
-        .output()?;
-    if !output.status.success() {
-        return Err(format!("Failed to extract language. Exit status: {}", output.status).into());
-    }
-    Ok(str::from_utf8(&output.stdout)?.trim().to_string())
-}
-
-async fn download_video(url: &str, lang: &str) -> Result<String, Box<dyn Error>> {
-    let file_name = Uuid::new_v4();
-    let output = Command::new("/usr/local/bin/yt-dlp")
-        .arg("--write-auto-subs")
-        .arg("--sub-lang")
-        .arg(lang)
-        .arg("--skip-download")
-        .arg("--no-live-from-start")
-        .arg("--convert-subs")
-        .arg("srt")
-        .arg("-o")
-        .arg(format!("./tmp/{}", file_name))
-        .arg(url)
-        .output()?;
-    if !output.status.success() {
-        return Err(format!("Failed to download video. Exit status: {}", output.status).into());
-    }
-    Ok(format!("{}.{}.srt", file_name, lang))
-}
-
-async fn process_message(bot: &Bot, msg: Message, rl_wrap: &rate_limiter::RateLimiterWrapper) -> Result<(), Box<dyn Error>>  {
-    
-        let txt = msg.text().ok_or("No text in message")?;
-        let user = msg.from.as_ref().ok_or("No user information in message")?;
-        let username = user.username.as_deref().ok_or("No username in user information")?;
-        
-        log::info!("From sender {} Received message: {}", username ,txt);
-        if txt == "/start" {
-            bot.send_message(msg.chat.id, "Send me a YouTube link and get a list of the books mentioned in the video. English and Russian are supported. Other languages have yet to be appropriately tested but are available, I guess.")
+    let listener = update_listeners::polling_default(bot.clone()).await;
+    let videos = Videos { pipeline, queue: job_queue(), jobs: cancel::CancelRegistry::new(), history, picks: tracks::PendingPicks::new() };
+    dispatch(bot, videos, settings, listener).await;
+}
+
+struct WebhookUpdates {
+    rx: mpsc::UnboundedReceiver<Update>,
+    stop_token: StopToken,
+    stop_flag: StopFlag,
+}
+
+fn webhook_updates(state: &mut WebhookUpdates) -> impl Stream<Item = Result<Update, Infallible>> + Send + '_ {
+    let stop_flag = state.stop_flag.clone();
+    futures::stream::poll_fn(move |cx| state.rx.poll_recv(cx).map(|update| update.map(Ok)))
+        .take_until(stop_flag)
+}
+
+/// Feeds updates received by the warp server into the teloxide dispatcher.
+fn webhook_listener(rx: mpsc::UnboundedReceiver<Update>) -> impl UpdateListener<Err = Infallible> {
+    let (stop_token, stop_flag) = mk_stop_token();
+    StatefulListener::new(
+        WebhookUpdates { rx, stop_token, stop_flag },
+        webhook_updates,
+        |state: &mut WebhookUpdates| state.stop_token.clone(),
+    )
+}
+
+/// Handles incoming messages and button presses; the heavy lifting is pushed to the job queue.
+async fn dispatch<L>(bot: Bot, videos: Videos, settings: settings::SettingsStore, listener: L)
+where
+    L: UpdateListener + Send,
+    L::Err: Debug,
+{
+    for lang in i18n::SUPPORTED {
+        let mut request = bot.set_my_commands(lang.commands(Command::bot_commands()));
+        // English is the menu for every client language without a translation
+        if lang != i18n::Lang::En {
+            request = request.language_code(lang.code());
+        }
+        if let Err(err) = request.await {
+            log::error!("Failed to register bot commands for {}: {}", lang.code(), err);
+        }
+    }
+    let handler = dptree::entry()
+        .branch(
+            Update::filter_message()
+                .branch(dptree::entry().filter_command::<Command>().endpoint(handle_command))
+                .branch(dptree::endpoint(handle_message)),
+        )
+        .branch(Update::filter_callback_query().endpoint(handle_callback));
+    Dispatcher::builder(bot, handler)
+        .dependencies(dptree::deps![videos, settings])
+        .default_handler(|_| async {})
+        .enable_ctrlc_handler()
+        .build()
+        .dispatch_with_listener(listener, LoggingErrorHandler::with_custom_text("An error from the update listener"))
+        .await;
+}
+
+async fn handle_command(bot: Bot, msg: Message, cmd: Command, videos: Videos, settings: settings::SettingsStore) -> ResponseResult<()> {
+    process_command(&bot, msg, cmd, &videos, &settings).await.map_err(|e| {
+        log::error!("Failed to process command: {:?}", e);
+    }).ok();
+    Ok(())
+}
+
+async fn handle_message(bot: Bot, msg: Message, videos: Videos, settings: settings::SettingsStore) -> ResponseResult<()> {
+    process_message(&bot, msg, &videos, &settings).await.map_err(|e| {
+        log::error!("Failed to process message: {:?}", e);
+    }).ok(); 
+    Ok(())
+}
+
+async fn handle_callback(bot: Bot, q: CallbackQuery, videos: Videos, settings: settings::SettingsStore) -> ResponseResult<()> {
+    process_callback(&bot, q, &videos, &settings).await.map_err(|e| {
+        log::error!("Failed to process callback query: {:?}", e);
+    }).ok();
+    Ok(())
+}
+
+async fn process_callback(bot: &Bot, q: CallbackQuery, videos: &Videos, settings: &settings::SettingsStore) -> Result<(), Box<dyn Error>> {
+    let data = q.data.as_deref().unwrap_or_default();
+    if let Some(action) = settings::parse_callback(data) {
+        return change_setting(bot, &q, action, settings).await;
+    }
+    let prefs = settings.get(q.from.id.0);
+    let lang = reply_lang(&q.from, &prefs);
+    if let Some(action) = history::parse_callback(data) {
+        return browse_history(bot, &q, action, &prefs, &videos.history).await;
+    }
+    if let Some((request_id, index)) = tracks::parse_callback(data) {
+        return pick_track(bot, &q, request_id, index, videos, lang).await;
+    }
+    let Some(request_id) = cancel::parse_callback(data) else {
+        return Ok(());
+    };
+    if !videos.jobs.cancel(request_id, q.from.id.0) {
+        bot.answer_callback_query(q.id.clone())
+            .text(lang.nothing_to_cancel())
+            .await?;
+        return Ok(());
+    }
+    log::info!("User {} cancelled request {}", q.from.id, request_id);
+    videos.picks.remove(request_id);
+    bot.answer_callback_query(q.id.clone())
+        .text(lang.cancelled())
+        .await?;
+    if let Some(status) = q.regular_message() {
+        bot.edit_message_text(status.chat.id, status.id, lang.cancelled())
+            .await?;
+    }
+    Ok(())
+}
+
+/// Continues a video whose subtitle track the user has just picked.
+async fn pick_track(bot: &Bot, q: &CallbackQuery, request_id: &str, index: usize, videos: &Videos, lang: i18n::Lang) -> Result<(), Box<dyn Error>> {
+    let pending = videos.picks.take(request_id, q.from.id.0);
+    let Some((pending, track)) = pending.and_then(|pending| {
+        let track = pending.options.get(index).cloned()?;
+        Some((pending, track))
+    }) else {
+        bot.answer_callback_query(q.id.clone())
+            .text(lang.track_gone())
+            .await?;
+        return Ok(());
+    };
+    bot.answer_callback_query(q.id.clone()).await?;
+    let PendingVideo { request, info, .. } = pending;
+    log::info!("User {} picked {:?} subtitles {} for {}", q.from.id, track.kind, track.key, request.video_id);
+    bot.edit_message_text(request.status.chat.id, request.status.id, lang.link_received())
+        .reply_markup(request.keyboard.clone())
+        .await?;
+    enqueue(bot, videos, PreparedVideo { request, info, track }).await?;
+    Ok(())
+}
+
+async fn change_setting(bot: &Bot, q: &CallbackQuery, action: settings::MenuAction, settings: &settings::SettingsStore) -> Result<(), Box<dyn Error>> {
+    let prefs = settings.update(q.from.id.0, |prefs| action.apply(prefs))?;
+    // Taken after the update so switching the reply language redraws the menu in the new one
+    let lang = reply_lang(&q.from, &prefs);
+    bot.answer_callback_query(q.id.clone()).await?;
+    if let Some(menu) = q.regular_message() {
+        bot.edit_message_text(menu.chat.id, menu.id, settings::describe(&prefs, lang))
+            .reply_markup(settings::keyboard(&prefs, lang))
+            .await?;
+    }
+    Ok(())
+}
+
+async fn browse_history(bot: &Bot, q: &CallbackQuery, action: history::HistoryAction, prefs: &settings::UserSettings, history: &history::History) -> Result<(), Box<dyn Error>> {
+    let lang = reply_lang(&q.from, prefs);
+    bot.answer_callback_query(q.id.clone()).await?;
+    let Some(menu) = q.regular_message() else {
+        return Ok(());
+    };
+    match action {
+        // In a group the menu is everyone's to see but only its owner's to page through
+        history::HistoryAction::Page(owner, _) if owner != q.from.id.0 => {}
+        history::HistoryAction::Page(owner, page) => {
+            let (entries, has_more) = history.page(owner, page)?;
+            bot.edit_message_text(menu.chat.id, menu.id, lang.history_title())
+                .reply_markup(history::keyboard(&entries, owner, page, has_more, lang))
+                .await?;
+        }
+        history::HistoryAction::Open(id) => {
+            let entry = history.get(q.from.id.0, id)?;
+            let Some(entry) = entry else {
+                bot.send_message(menu.chat.id, lang.history_gone()).await?;
+                return Ok(());
+            };
+            // Shown straight from the stored result, nothing is extracted again
+            let header = lang.history_entry(&entry.title, &entry.video_id, &entry.lang);
+            let list = BookList { video_id: &entry.video_id, books: &entry.books, entry_id: Some(entry.id) };
+            send_books(bot, menu.chat.id, &header, &list, prefs, lang).await?;
+        }
+        history::HistoryAction::Why(id, index) => {
+            let entry = history.get_in_chat(q.from.id.0, menu.chat.id.0, id)?;
+            let Some((entry, book)) = entry.as_ref().and_then(|entry| Some((entry, entry.books.get(index)?))) else {
+                bot.send_message(menu.chat.id, lang.history_gone()).await?;
+                return Ok(());
+            };
+            bot.send_message(menu.chat.id, format_excerpt(book, &entry.video_id, lang))
+                .parse_mode(ParseMode::Html)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn show_history(bot: &Bot, chat_id: ChatId, user_id: u64, history: &history::History, lang: i18n::Lang) -> Result<(), Box<dyn Error>> {
+    let (entries, has_more) = history.page(user_id, 0)?;
+    if entries.is_empty() {
+        bot.send_message(chat_id, lang.history_empty()).await?;
+        return Ok(());
+    }
+    bot.send_message(chat_id, lang.history_title())
+        .reply_markup(history::keyboard(&entries, user_id, 0, has_more, lang))
+        .await?;
+    Ok(())
+}
+
+/// The user's numeric ID, which is what identifies them, followed by a name for reading logs.
+/// Many users have no @username, so it is never required.
+fn display_name(user: &User) -> String {
+    match &user.username {
+        Some(username) => format!("{} (@{})", user.id, username),
+        None => format!("{} ({})", user.id, user.full_name()),
+    }
+}
+
+/// Subtitle languages the user can read, most wanted first: the fallback from the settings,
+/// the reply language and the language of the Telegram client.
+fn subtitle_langs(user: &User, prefs: &settings::UserSettings) -> Vec<String> {
+    let client = user.language_code.as_deref().and_then(|code| code.split('-').next());
+    prefs.lang.iter().chain(prefs.reply_lang.iter()).cloned()
+        .chain(client.map(str::to_string))
+        .collect()
+}
+
+/// Language to reply to the user in.
+fn reply_lang(user: &User, prefs: &settings::UserSettings) -> i18n::Lang {
+    i18n::Lang::pick(prefs.reply_lang.as_deref(), user.language_code.as_deref())
+}
+
+fn is_admin(user_id: u64) -> bool {
+    env::var("ADMIN_IDS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|id| id.trim().parse::<u64>().ok())
+        .any(|id| id == user_id)
+}
+
+fn link_regex() -> Regex {
+    Regex::new(r"(?im)^(?:https?:\/\/)?(?:www\.)?(?:youtube\.com\/(?:watch\?v=|embed\/|v\/|shorts\/)|youtu\.be\/)([\w\-]{11})(?:\S*)?")
+        .expect("Invalid regular expression")
+}
+
+async fn invalidate_cache(bot: &Bot, msg: &Message, arg: &str, cache: &cache::ResultCache, lang: i18n::Lang) -> Result<(), Box<dyn Error>> {
+    let video_id = match link_regex().captures(arg) {
+        Some(caps) => caps[1].to_string(),
+        None => arg.to_string(),
+    };
+    if video_id.len() != 11 {
+        bot.send_message(msg.chat.id, lang.invalidate_usage())
+            .await?;
+        return Ok(());
+    }
+    let removed = cache.invalidate(&video_id)?;
+    log::info!("Invalidated {} cache entries for video {}", removed, video_id);
+    bot.send_message(msg.chat.id, lang.invalidated(removed, &video_id))
+        .await?;
+    Ok(())
+}
+
+/// Edits the status message with the job's place in the queue until a worker picks it up,
+/// from then on the job itself reports its progress there.
+async fn report_queue_position(bot: Bot, status: Message, keyboard: InlineKeyboardMarkup, lang: i18n::Lang, mut position: watch::Receiver<usize>, cancel: CancellationToken) {
+    let mut reported = 0;
+    loop {
+        let current = *position.borrow_and_update();
+        if current == 0 {
+            return;
+        }
+        if current != reported {
+            reported = current;
+            let text = lang.queue_position(current);
+            if let Err(err) = bot.edit_message_text(status.chat.id, status.id, text)
+                .reply_markup(keyboard.clone())
                 .await
-                .unwrap();
+            {
+                log::error!("Failed to update queue position: {}", err);
+            }
+        }
+        tokio::select! {
+            changed = position.changed() => if changed.is_err() {
+                return;
+            },
+            _ = cancel.cancelled() => return,
+        }
+    }
+}
+
+fn format_time(seconds: u64) -> String {
+    if seconds >= 3600 {
+        format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+/// The book's title in HTML, linking to the moment of its first mention in the video.
+fn linked_title(book: &extract_json::Book, video_id: &str) -> String {
+    match book.mentions.first() {
+        Some(time) => html::link(&format!("https://youtu.be/{}?t={}", video_id, time), &html::escape(&book.title)),
+        None => html::escape(&book.title),
+    }
+}
+
+/// `12:40 — Favourite sci-fi` in HTML, the time linking to the start of the chapter.
+fn chapter_heading(chapter: &extract_json::BookChapter, video_id: &str) -> String {
+    let start = html::link(&format!("https://youtu.be/{}?t={}", video_id, chapter.start), &format_time(chapter.start));
+    html::bold(&format!("{} — {}", start, html::escape(&chapter.title)))
+}
+
+/// Telegram refuses longer messages. It counts UTF-16 code units of the text, the HTML is measured
+/// instead as it is never shorter.
+const MESSAGE_LIMIT: usize = 4096;
+
+fn message_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// One line of the list in HTML.
+fn format_book(i: usize, r: &extract_json::Book, video_id: &str, prefs: &settings::UserSettings, lang: i18n::Lang) -> String {
+    let title = linked_title(r, video_id);
+    let mut line = match prefs.format {
+        settings::OutputFormat::Plain => format!("{} \"{}\"", html::escape(&r.author), title),
+        settings::OutputFormat::Numbered => format!("{}. {} \"{}\"", i + 1, html::escape(&r.author), title),
+    };
+    let mut details = vec![];
+    if let (true, Some(time)) = (prefs.timestamps, r.mentions.first()) {
+        details.push(format_time(*time));
+    }
+    if r.mentions.len() > 1 {
+        details.push(lang.more_mentions(r.mentions.len() - 1));
+    }
+    if !details.is_empty() {
+        line += &format!(" ({})", details.join(", "));
+    }
+    line + "\n"
+}
+
+/// HTML list of the books under `header`, with a heading for each chapter they were found in, cut between
+/// books into messages Telegram accepts. Each message comes with the indices of the books it shows
+/// and repeats the heading of a chapter it continues.
+fn format_books(header: &str, books: &[extract_json::Book], video_id: &str, prefs: &settings::UserSettings, lang: i18n::Lang) -> Vec<(String, Range<usize>)> {
+    let mut messages = vec![];
+    let mut message = if header.is_empty() { String::new() } else { format!("{}\n\n", header) };
+    let mut first = 0;
+    let mut chapter = None;
+    for (i, r) in books.iter().enumerate() {
+        let line = format_book(i, r, video_id, prefs, lang);
+        // Books come sorted by chapter, those without one first
+        let heading = |starts_message: bool| match &r.chapter {
+            Some(heading) if starts_message || r.chapter.as_ref() != chapter => {
+                let gap = if starts_message { "" } else { "\n" };
+                format!("{}{}\n", gap, chapter_heading(heading, video_id))
+            }
+            _ => String::new(),
+        };
+        let mut block = heading(i == first) + &line;
+        if i > first && message_len(&message) + message_len(&block) > MESSAGE_LIMIT {
+            messages.push((std::mem::take(&mut message), first..i));
+            first = i;
+            block = heading(true) + &line;
+        }
+        message += &block;
+        chapter = r.chapter.as_ref();
+    }
+    messages.push((message, first..books.len()));
+    messages
+}
+
+/// The book followed by what was said around its first mention, in HTML.
+fn format_excerpt(book: &extract_json::Book, video_id: &str, lang: i18n::Lang) -> String {
+    let excerpt = book.excerpt.as_deref().unwrap_or(lang.no_excerpt());
+    format!(
+        "{} \"{}\"\n<blockquote>{}</blockquote>",
+        html::escape(&book.author),
+        linked_title(book, video_id),
+        html::escape(excerpt),
+    )
+}
+
+/// Title, channel and length of the video, shown above the list of books.
+fn video_header(info: &ytdlp::VideoInfo) -> String {
+    let mut details = vec![];
+    if let Some(channel) = info.channel.as_ref().or(info.uploader.as_ref()) {
+        details.push(channel.clone());
+    }
+    if let Some(duration) = info.duration {
+        details.push(format_time(duration as u64));
+    }
+    [info.title.clone(), details.join(" · ")]
+        .into_iter()
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Books found in a video, as sent to the user.
+struct BookList<'a> {
+    video_id: &'a str,
+    books: &'a [extract_json::Book],
+    /// History entry the list is stored in, the Why? buttons read the excerpts from there.
+    entry_id: Option<i64>,
+}
+
+async fn send_books(bot: &Bot, chat_id: ChatId, header: &str, list: &BookList<'_>, prefs: &settings::UserSettings, lang: i18n::Lang) -> ResponseResult<()> {
+    let header = html::escape(header);
+    if list.books.is_empty() {
+        let text = if header.is_empty() { html::escape(lang.no_books()) } else { format!("{}\n\n{}", header, html::escape(lang.no_books())) };
+        bot.send_message(chat_id, text)
+            .parse_mode(ParseMode::Html)
+            .await?;
+    } else {
+        // Long lists go out in several messages, each with the Why? buttons of its own books
+        for (text, shown) in format_books(&header, list.books, list.video_id, prefs, lang) {
+            let mut message = bot.send_message(chat_id, text)
+                .parse_mode(ParseMode::Html);
+            if let Some(keyboard) = list.entry_id.and_then(|id| history::why_keyboard(id, list.books, shown, lang)) {
+                message = message.reply_markup(keyboard);
+            }
+            message.await?;
+        }
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        bot.send_message(chat_id, lang.all_found())
+            .await?;
+    }
+    Ok(())
+}
+
+/// A YouTube link sent by a user.
+#[derive(Clone)]
+struct VideoRequest {
+    request_id: String,
+    user_id: u64,
+    url: String,
+    video_id: String,
+    status: Message,
+    keyboard: InlineKeyboardMarkup,
+    prefs: settings::UserSettings,
+    lang: i18n::Lang,
+    /// Subtitle languages to try after the video's own.
+    subtitle_langs: Vec<String>,
+    cancel: CancellationToken,
+}
+
+/// A video waiting for the user to pick one of several subtitle tracks.
+#[derive(Clone)]
+struct PendingVideo {
+    request: VideoRequest,
+    info: ytdlp::VideoInfo,
+    options: Vec<tracks::Track>,
+}
+
+/// A video with its track settled, waiting in the queue or being processed.
+struct PreparedVideo {
+    request: VideoRequest,
+    info: ytdlp::VideoInfo,
+    track: tracks::Track,
+}
+
+/// Looks the video up, then queues it right away or, when it has several tracks, asks which one to read.
+async fn lookup_video(bot: Bot, videos: Videos, request: VideoRequest) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let lang = request.lang;
+    let status = request.status.clone();
+    let found = videos.pipeline.lookup(&request.url, &request.video_id, &request.cancel).await;
+    let preferred = found.as_ref().ok().and_then(|info| videos.pipeline.choose_track(info, &request.subtitle_langs));
+    let (info, track) = match (found, preferred) {
+        (Ok(info), Some(track)) => (info, track),
+        (found, _) => {
+            videos.jobs.remove(&request.request_id);
+            let err = found.err().unwrap_or(error::ExtractError::NoSubtitles);
+            // The Cancel button has already replaced the status
+            if err != error::ExtractError::Cancelled {
+                log::error!("Error looking up {} [{}]: {}", request.video_id, err.label(), err);
+                bot.edit_message_text(status.chat.id, status.id, lang.failure(&err))
+                    .await?;
+            }
             return Ok(());
         }
-        if txt == "/help" {
-            bot.send_message(msg.chat.id, "Send me a YouTube link and get a list of the books mentioned in the video. English and Russian are supported. Other languages have yet to be appropriately tested but are available, I guess.")
-                .await
-                .unwrap();
+    };
+    let options = tracks::options(&info, Some(&track));
+    // Books already found in the preferred track are sent without asking
+    if options.len() < 2 || videos.pipeline.cached(&request.video_id, &track).is_some() {
+        enqueue(&bot, &videos, PreparedVideo { request, info, track }).await?;
+        return Ok(());
+    }
+    bot.edit_message_text(status.chat.id, status.id, lang.pick_track())
+        .reply_markup(tracks::keyboard(&request.request_id, &options, lang))
+        .await?;
+    let (request_id, user_id) = (request.request_id.clone(), request.user_id);
+    // One pending choice per user and chat, an older one is given up
+    if let Some(previous) = videos.picks.insert(&request_id, status.chat.id, user_id, PendingVideo { request, info, options }) {
+        log::info!("Request {} superseded while picking a track", previous.request.request_id);
+        videos.jobs.remove(&previous.request.request_id);
+        let previous = &previous.request;
+        bot.edit_message_text(previous.status.chat.id, previous.status.id, previous.lang.cancelled())
+            .await?;
+    }
+    tokio::spawn(expire_pick(bot, videos, request_id));
+    Ok(())
+}
+
+/// Gives up the pick if its user has not made it in time, so it does not hold its video forever.
+async fn expire_pick(bot: Bot, videos: Videos, request_id: String) {
+    tokio::time::sleep(tracks::PICK_TIMEOUT).await;
+    let Some(expired) = videos.picks.remove(&request_id) else {
+        return;
+    };
+    log::info!("Request {} expired while picking a track", request_id);
+    videos.jobs.remove(&request_id);
+    let request = &expired.request;
+    if let Err(err) = bot.edit_message_text(request.status.chat.id, request.status.id, request.lang.cancelled()).await {
+        log::error!("Failed to report the expired track pick: {}", err);
+    }
+}
+
+/// Puts a video into the job queue and reports its position there.
+async fn enqueue(bot: &Bot, videos: &Videos, video: PreparedVideo) -> ResponseResult<()> {
+    let request = video.request.clone();
+    // A cached list needs neither yt-dlp nor the LLM, and a job someone else runs already has its worker,
+    // so neither waits in the queue
+    let cached = videos.pipeline.cached(&request.video_id, &video.track).is_some();
+    let joined = if cached { None } else { videos.pipeline.join(&request.video_id, &video.track) };
+    let skips_queue = cached || joined.is_some();
+    let job = {
+        let bot = bot.clone();
+        let pipeline = videos.pipeline.clone();
+        let history = videos.history.clone();
+        let jobs = videos.jobs.clone();
+        async move {
+            let request_id = video.request.request_id.clone();
+            // Cancelled while still waiting in the queue
+            if video.request.cancel.is_cancelled() {
+                if let Some(flight) = joined {
+                    flight.abandon();
+                }
+            } else if let Err(err) = deliver_books(bot, pipeline, history, video, joined).await {
+                log::error!("Failed to deliver books: {}", err);
+            }
+            jobs.remove(&request_id);
+        }
+    };
+    if skips_queue {
+        tokio::spawn(job);
+        return Ok(());
+    }
+    match videos.queue.push(request.user_id, job) {
+        Ok(position) => {
+            tokio::spawn(report_queue_position(bot.clone(), request.status, request.keyboard, request.lang, position, request.cancel));
+        }
+        Err(queue::UserLimitReached) => {
+            videos.jobs.remove(&request.request_id);
+            log::info!("User {} hit the per-user job limit", request.user_id);
+            bot.edit_message_text(request.status.chat.id, request.status.id, request.lang.too_many_jobs())
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Follows the job extracting the video, `joined` when one was already running, and sends its books.
+async fn deliver_books(bot: Bot, pipeline: pipeline::Pipeline, history: history::History, video: PreparedVideo, joined: Option<pipeline::Flight>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let PreparedVideo { request, info, track } = video;
+    let VideoRequest { user_id, url, video_id, status, keyboard, prefs, lang, cancel, .. } = request;
+    let chat_id = status.chat.id;
+    let flight = match joined {
+        Some(flight) => flight,
+        None => pipeline.extract(&url, &video_id, info, track),
+    };
+    let followed = tokio::select! {
+        res = progress::follow(&bot, &status, &keyboard, lang, flight.stage.clone(), flight.result.clone()) => res,
+        _ = cancel.cancelled() => {
+            log::info!("Request for {} cancelled", video_id);
+            flight.abandon();
+            return Ok(());
+        }
+    };
+    let extraction = match followed {
+        Ok(extraction) => {
+            bot.edit_message_text(chat_id, status.id, lang.done(&extraction.lang, extraction.kind))
+                .await?;
+            extraction
+        }
+        Err(err) => {
+            log::error!("Error extracting books from {} [{}]: {}", video_id, err.label(), err);
+            bot.edit_message_text(chat_id, status.id, lang.failure(&err))
+                .await?;
             return Ok(());
         }
-        let re = Regex::new(r"(?im)^(?:https?:\/\/)?(?:www\.)?(?:youtube\.com\/(?:watch\?v=|embed\/|v\/|shorts\/)|youtu\.be\/)([\w\-]{11})(?:\S*)?")
-            .expect("Invalid regular expression");
+    };
+    let title = if extraction.info.title.is_empty() { &video_id } else { &extraction.info.title };
+    let entry_id = match history.record(user_id, chat_id.0, &video_id, title, &extraction.lang, &extraction.books) {
+        Ok(id) => Some(id),
+        Err(err) => {
+            log::error!("Failed to record history of {}: {}", user_id, err);
+            None
+        }
+    };
+    let list = BookList { video_id: &video_id, books: &extraction.books, entry_id };
+    send_books(&bot, chat_id, &video_header(&extraction.info), &list, &prefs, lang).await?;
+    Ok(())
+}
+
+async fn process_command(bot: &Bot, msg: Message, cmd: Command, videos: &Videos, settings: &settings::SettingsStore) -> Result<(), Box<dyn Error>> {
+    let user = msg.from.as_ref().ok_or("No user information in message")?;
+    let sender = display_name(user);
+    log::info!("From sender {} Received command: {}", sender, msg.text().unwrap_or_default());
+    let prefs = settings.get(user.id.0);
+    let lang = reply_lang(user, &prefs);
+
+    match cmd {
+        Command::Start => {
+            bot.send_message(msg.chat.id, lang.about()).await?;
+        }
+        Command::Help => {
+            bot.send_message(msg.chat.id, lang.help(Command::bot_commands())).await?;
+        }
+        Command::Settings => {
+            bot.send_message(msg.chat.id, settings::describe(&prefs, lang))
+                .reply_markup(settings::keyboard(&prefs, lang))
+                .await?;
+        }
+        Command::History => {
+            show_history(bot, msg.chat.id, user.id.0, &videos.history, lang).await?;
+        }
+        Command::Lang(code) => {
+            let code = code.trim().to_lowercase();
+            if code.is_empty() || code.len() > 10 || !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                bot.send_message(msg.chat.id, lang.lang_usage()).await?;
+                return Ok(());
+            }
+            let prefs = settings.update(user.id.0, |prefs| {
+                prefs.lang = if code == "none" { None } else { Some(code) };
+            })?;
+            bot.send_message(msg.chat.id, settings::describe(&prefs, lang)).await?;
+        }
+        Command::Format(name) => match name.parse::<settings::OutputFormat>() {
+            Ok(format) => {
+                let prefs = settings.update(user.id.0, |prefs| prefs.format = format)?;
+                bot.send_message(msg.chat.id, settings::describe(&prefs, lang)).await?;
+            }
+            Err(_) => {
+                bot.send_message(msg.chat.id, lang.format_usage()).await?;
+            }
+        },
+        Command::Invalidate(arg) => {
+            if !is_admin(user.id.0) {
+                log::info!("Non-admin {} tried to invalidate the cache", sender);
+                return Ok(());
+            }
+            invalidate_cache(bot, &msg, arg.trim(), &videos.pipeline.cache, lang).await?;
+        }
+    }
+    Ok(())
+}
 
-        if let Some(url) = re.captures(txt) {
+async fn process_message(bot: &Bot, msg: Message, videos: &Videos, settings: &settings::SettingsStore) -> Result<(), Box<dyn Error>>  {
+    
+        let txt = msg.text().ok_or("No text in message")?;
+        let user = msg.from.as_ref().ok_or("No user information in message")?;
+        let sender = display_name(user);
+        
+        log::info!("From sender {} Received message: {}", sender ,txt);
+        let prefs = settings.get(user.id.0);
+        let lang = reply_lang(user, &prefs);
+
+        if let Some(url) = link_regex().captures(txt) {
             log::info!("Whole match: {}", &url[0]);
-            bot.send_message(msg.chat.id, "Valid YouTube link received. Hold the line")
-                .await
-                .unwrap();
-            let lang = extract_lang(&url[0]).await?;
-            let file_name = download_video(&url[0], &lang).await?;
-            bot.send_message(msg.chat.id, format!("Language: {}", lang))
-                .await
-                .unwrap();
-            let books = match extract_json::extract_json(&file_name, &env::var("OPENAI_TOKEN").unwrap(), rl_wrap).await {
-                Ok(books) => books,
-                Err(err) => {
-                    log::error!("Error extracting JSON: {:?}", err);
-                    return Ok(());
-                }
+            let (request_id, cancel) = videos.jobs.register(user.id.0);
+            let keyboard = cancel::keyboard(&request_id, lang);
+            let status = bot.send_message(msg.chat.id, lang.link_received())
+                .reply_markup(keyboard.clone())
+                .await?;
+            let request = VideoRequest {
+                request_id,
+                user_id: user.id.0,
+                url: url[0].to_string(),
+                video_id: url[1].to_string(),
+                status,
+                keyboard,
+                subtitle_langs: subtitle_langs(user, &prefs),
+                prefs,
+                lang,
+                cancel,
             };
-            
-            if books.len() == 0 {
-                bot.send_message(msg.chat.id, "No books or authors found in the video.")
-                    .await
-                    .unwrap();
-            } else {
-                let mut message = String::new();
-                for r in books {
-                    message += &format!("{} \"{}\"\n", r.author, r.title);
+            let bot = bot.clone();
+            let videos = videos.clone();
+            tokio::spawn(async move {
+                if let Err(err) = lookup_video(bot, videos, request).await {
+                    log::error!("Failed to look up video: {}", err);
                 }
-                bot.send_message(msg.chat.id, message)
-                    .await
-                    .unwrap();
-                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-                bot.send_message(msg.chat.id, "That's all I could find. Hope it helps!")
-                    .await
-                    .unwrap();
-            }
-            
-            
+            });
         } else {
-            bot.send_message(msg.chat.id, "Does not look like a YouTube link. Try one more time.")
+            bot.send_message(msg.chat.id, lang.not_a_link())
                 .await
                 .unwrap();
         }
@@ -230,3 +913,35 @@ async fn process_message(bot: &Bot, msg: Message, rl_wrap: &rate_limiter::RateLi
         Ok(())
     
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(i: usize, chapter: &str) -> extract_json::Book {
+        extract_json::Book {
+            author: format!("Author {}", i),
+            title: format!("A rather long book title number {} to fill the message up", i),
+            mentions: vec![i as u64 * 60],
+            excerpt: None,
+            chapter: Some(extract_json::BookChapter { title: chapter.to_string(), start: 0 }),
+        }
+    }
+
+    #[test]
+    fn long_lists_are_cut_between_books() {
+        let books: Vec<_> = (0..100).map(|i| book(i, "Fiction")).collect();
+        let messages = format_books("Reading list", &books, "abcdefghijk", &settings::UserSettings::default(), i18n::Lang::En);
+        assert!(messages.len() > 1);
+        assert!(messages[0].0.starts_with("Reading list\n\n"));
+        let mut next = 0;
+        for (text, shown) in &messages {
+            assert!(message_len(text) <= MESSAGE_LIMIT);
+            assert_eq!(shown.start, next);
+            // Every message names the chapter it continues
+            assert!(text.contains("Fiction"));
+            next = shown.end;
+        }
+        assert_eq!(next, books.len());
+    }
+}
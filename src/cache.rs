@@ -0,0 +1,190 @@
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::extract_json::Book;
+use crate::ytdlp::VideoInfo;
+
+/// On-disk cache of extraction results.
+///
+/// Entries are keyed by YouTube video ID, subtitle track and prompt version,
+/// so changing the prompt naturally invalidates everything produced by the old one.
+/// The video's metadata is kept next to them, so a cached video needs no yt-dlp lookup.
+pub struct ResultCache {
+    conn: Arc<Mutex<Connection>>,
+    ttl: Duration,
+}
+
+impl Clone for ResultCache {
+    fn clone(&self) -> Self {
+        ResultCache {
+            conn: Arc::clone(&self.conn),
+            ttl: self.ttl,
+        }
+    }
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+impl ResultCache {
+    pub fn open(path: &str, ttl: Duration) -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS track_results (
+                video_id TEXT NOT NULL,
+                track TEXT NOT NULL,
+                prompt_version INTEGER NOT NULL,
+                books TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (video_id, track, prompt_version)
+            );
+            CREATE TABLE IF NOT EXISTS video_info (
+                video_id TEXT PRIMARY KEY,
+                info TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            );",
+        )?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            ttl,
+        })
+    }
+
+    /// `track` is the track's `Track::id`.
+    pub fn get(&self, video_id: &str, track: &str, prompt_version: u32) -> Result<Option<Vec<Book>>, Box<dyn Error>> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let not_before = now_secs() - self.ttl.as_secs() as i64;
+        let row: Option<String> = conn
+            .query_row(
+                "SELECT books FROM track_results
+                 WHERE video_id = ?1 AND track = ?2 AND prompt_version = ?3 AND created_at >= ?4",
+                params![video_id, track, prompt_version, not_before],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match row {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put(&self, video_id: &str, track: &str, prompt_version: u32, books: &[Book]) -> Result<(), Box<dyn Error>> {
+        let json = serde_json::to_string(books)?;
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT OR REPLACE INTO track_results (video_id, track, prompt_version, books, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![video_id, track, prompt_version, json, now_secs()],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_info(&self, video_id: &str) -> Result<Option<VideoInfo>, Box<dyn Error>> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let not_before = now_secs() - self.ttl.as_secs() as i64;
+        let row: Option<String> = conn
+            .query_row(
+                "SELECT info FROM video_info WHERE video_id = ?1 AND created_at >= ?2",
+                params![video_id, not_before],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match row {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put_info(&self, video_id: &str, info: &VideoInfo) -> Result<(), Box<dyn Error>> {
+        let json = serde_json::to_string(info)?;
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT OR REPLACE INTO video_info (video_id, info, created_at) VALUES (?1, ?2, ?3)",
+            params![video_id, json, now_secs()],
+        )?;
+        Ok(())
+    }
+
+    /// Drops every cached result for the video, whatever the track or prompt version, and its metadata.
+    /// Returns how many results were dropped.
+    pub fn invalidate(&self, video_id: &str) -> Result<usize, Box<dyn Error>> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute("DELETE FROM video_info WHERE video_id = ?1", params![video_id])?;
+        Ok(conn.execute("DELETE FROM track_results WHERE video_id = ?1", params![video_id])?)
+    }
+
+    pub fn purge_expired(&self) -> Result<usize, Box<dyn Error>> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let not_before = now_secs() - self.ttl.as_secs() as i64;
+        conn.execute("DELETE FROM video_info WHERE created_at < ?1", params![not_before])?;
+        Ok(conn.execute("DELETE FROM track_results WHERE created_at < ?1", params![not_before])?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(3600);
+
+    fn books(title: &str) -> Vec<Book> {
+        vec![Book { author: "Frank Herbert".to_string(), title: title.to_string(), mentions: vec![], excerpt: None, chapter: None }]
+    }
+
+    /// Moves every entry of the video back in time by `age`.
+    fn age(cache: &ResultCache, video_id: &str, age: Duration) {
+        let conn = cache.conn.lock().unwrap();
+        for table in ["track_results", "video_info"] {
+            conn.execute(
+                &format!("UPDATE {} SET created_at = created_at - ?1 WHERE video_id = ?2", table),
+                params![age.as_secs() as i64, video_id],
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn results_are_kept_per_track_and_prompt_version() {
+        let cache = ResultCache::open(":memory:", TTL).unwrap();
+        cache.put("video", "en:manual", 1, &books("Dune")).unwrap();
+        assert_eq!(cache.get("video", "en:manual", 1).unwrap(), Some(books("Dune")));
+        assert_eq!(cache.get("video", "en:auto", 1).unwrap(), None);
+        assert_eq!(cache.get("video", "en:manual", 2).unwrap(), None);
+        assert_eq!(cache.get("other", "en:manual", 1).unwrap(), None);
+    }
+
+    #[test]
+    fn expired_entries_are_not_returned_and_get_purged() {
+        let cache = ResultCache::open(":memory:", TTL).unwrap();
+        cache.put("old", "en:manual", 1, &books("Dune")).unwrap();
+        cache.put_info("old", &VideoInfo::default()).unwrap();
+        cache.put("new", "en:manual", 1, &books("Emma")).unwrap();
+        age(&cache, "old", TTL + Duration::from_secs(1));
+        assert_eq!(cache.get("old", "en:manual", 1).unwrap(), None);
+        assert!(cache.get_info("old").unwrap().is_none());
+
+        assert_eq!(cache.purge_expired().unwrap(), 1);
+        assert_eq!(cache.get("new", "en:manual", 1).unwrap(), Some(books("Emma")));
+    }
+
+    #[test]
+    fn invalidate_drops_every_track_of_the_video_only() {
+        let cache = ResultCache::open(":memory:", TTL).unwrap();
+        cache.put("video", "en:manual", 1, &books("Dune")).unwrap();
+        cache.put("video", "ru:auto", 1, &books("Dune")).unwrap();
+        cache.put_info("video", &VideoInfo::default()).unwrap();
+        cache.put("other", "en:manual", 1, &books("Emma")).unwrap();
+
+        assert_eq!(cache.invalidate("video").unwrap(), 2);
+        assert_eq!(cache.get("video", "en:manual", 1).unwrap(), None);
+        assert!(cache.get_info("video").unwrap().is_none());
+        assert_eq!(cache.get("other", "en:manual", 1).unwrap(), Some(books("Emma")));
+    }
+}
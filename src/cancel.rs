@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::i18n::Lang;
+
+const CALLBACK_PREFIX: &str = "cancel:";
+
+/// Cancellation tokens of user requests that are queued or running, keyed by request ID.
+pub struct CancelRegistry {
+    requests: Arc<Mutex<HashMap<String, (u64, CancellationToken)>>>,
+}
+
+impl Clone for CancelRegistry {
+    fn clone(&self) -> Self {
+        CancelRegistry {
+            requests: Arc::clone(&self.requests),
+        }
+    }
+}
+
+impl CancelRegistry {
+    pub fn new() -> Self {
+        Self {
+            requests: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Registers a new request of the user and returns its ID and token.
+    pub fn register(&self, user_id: u64) -> (String, CancellationToken) {
+        let request_id = Uuid::new_v4().to_string();
+        let token = CancellationToken::new();
+        self.requests
+            .lock()
+            .unwrap()
+            .insert(request_id.clone(), (user_id, token.clone()));
+        (request_id, token)
+    }
+
+    /// Cancels the request if it is still alive and belongs to the user.
+    pub fn cancel(&self, request_id: &str, user_id: u64) -> bool {
+        let mut requests = self.requests.lock().unwrap();
+        match requests.get(request_id) {
+            Some((owner, _)) if *owner == user_id => {
+                let (_, token) = requests.remove(request_id).unwrap();
+                token.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn remove(&self, request_id: &str) {
+        self.requests.lock().unwrap().remove(request_id);
+    }
+}
+
+pub fn keyboard(request_id: &str, lang: Lang) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new([[InlineKeyboardButton::callback(
+        lang.cancel_button(),
+        format!("{}{}", CALLBACK_PREFIX, request_id),
+    )]])
+}
+
+/// Extracts the request ID from the data of a Cancel button press.
+pub fn parse_callback(data: &str) -> Option<&str> {
+    data.strip_prefix(CALLBACK_PREFIX)
+}
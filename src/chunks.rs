@@ -0,0 +1,214 @@
+use std::collections::VecDeque;
+use std::env;
+use std::ops::Range;
+use std::sync::Arc;
+
+use tiktoken_rs::CoreBPE;
+
+/// Cuts transcripts into pieces that fit one LLM request, measured in the model's own tokens.
+pub struct Chunker {
+    bpe: Arc<CoreBPE>,
+    /// Transcript tokens sent per request, the prompt comes on top.
+    pub chunk_tokens: usize,
+    /// Tokens at the end of a chunk repeated at the start of the next, so a book named across
+    /// the boundary is seen whole at least once. At most half a chunk.
+    pub overlap_tokens: usize,
+    /// Tokens kept for the answer, sent as `max_tokens` and counted against the rate limit.
+    pub output_reserve: usize,
+}
+
+impl Clone for Chunker {
+    fn clone(&self) -> Self {
+        Chunker {
+            bpe: Arc::clone(&self.bpe),
+            chunk_tokens: self.chunk_tokens,
+            overlap_tokens: self.overlap_tokens,
+            output_reserve: self.output_reserve,
+        }
+    }
+}
+
+fn env_usize(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+impl Chunker {
+    /// Models tiktoken has no tokenizer for, like local ones, are measured with `o200k_base`,
+    /// close enough to size chunks.
+    pub fn new(model: &str, chunk_tokens: usize, overlap_tokens: usize, output_reserve: usize) -> Result<Self, String> {
+        let bpe = match tiktoken_rs::get_bpe_from_model(model) {
+            Ok(bpe) => bpe,
+            Err(_) => {
+                log::warn!("No tokenizer for model {}, counting tokens with o200k_base", model);
+                tiktoken_rs::o200k_base().map_err(|err| format!("failed to load o200k_base: {}", err))?
+            }
+        };
+        let chunk_tokens = chunk_tokens.max(1);
+        Ok(Self {
+            bpe: Arc::new(bpe),
+            chunk_tokens,
+            overlap_tokens: overlap_tokens.min(chunk_tokens / 2),
+            output_reserve,
+        })
+    }
+
+    /// Chunk sizes come from the environment, the tokenizer from the model.
+    pub fn from_env(model: &str) -> Result<Self, String> {
+        Self::new(
+            model,
+            env_usize("CHUNK_TOKENS", 4000),
+            env_usize("CHUNK_OVERLAP_TOKENS", 200),
+            env_usize("LLM_OUTPUT_TOKENS", 1000),
+        )
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        self.bpe.encode_ordinary(text).len()
+    }
+
+    /// Splits the transcript between lines, which are cues, into chunks of at most `chunk_tokens` tokens.
+    /// Each chunk starts with the last lines of the previous one, up to `overlap_tokens`.
+    /// A line longer than a chunk on its own is split between words, a word longer than that between characters.
+    /// The text is walked once and every piece of it tokenized a bounded number of times,
+    /// so a five-hour transcript costs five times a one-hour one.
+    pub fn split(&self, text: &str) -> Vec<String> {
+        let mut chunks = vec![];
+        // Lines of the chunk being filled with their token counts, one more for the line break
+        let mut chunk: VecDeque<(String, usize)> = VecDeque::new();
+        let mut tokens = 0;
+        for line in text.lines() {
+            for (piece, count) in self.pieces(line) {
+                let count = count + 1;
+                // Never empty here after a flush, the next line is always added right after it
+                if tokens + count > self.chunk_tokens && !chunk.is_empty() {
+                    chunks.push(join(&chunk));
+                    tokens = self.keep_tail(&mut chunk);
+                }
+                // The overlap gives way to a line that would not fit next to it
+                while tokens + count > self.chunk_tokens {
+                    match chunk.pop_front() {
+                        Some((_, dropped)) => tokens -= dropped,
+                        None => break,
+                    }
+                }
+                chunk.push_back((piece, count));
+                tokens += count;
+            }
+        }
+        if !chunk.is_empty() {
+            chunks.push(join(&chunk));
+        }
+        chunks
+    }
+
+    /// Splits a transcript cut into sections, like the chapters of a video, preferring to cut chunks
+    /// between sections: whole sections are packed into a chunk while they fit, a section that does not
+    /// starts the next one, and only a section longer than a chunk is split inside, with overlap.
+    /// Each chunk comes with the indices of the sections it holds.
+    pub fn split_sections(&self, sections: &[String]) -> Vec<(String, Range<usize>)> {
+        let mut chunks = vec![];
+        let mut chunk: Vec<&str> = vec![];
+        let mut held = 0..0;
+        let mut tokens = 0;
+        for (index, section) in sections.iter().map(|section| section.trim()).enumerate().filter(|(_, section)| !section.is_empty()) {
+            let count = self.count(section) + 1;
+            if tokens + count > self.chunk_tokens && !chunk.is_empty() {
+                chunks.push((chunk.join("\n"), held.clone()));
+                chunk.clear();
+                tokens = 0;
+            }
+            if count > self.chunk_tokens {
+                chunks.extend(self.split(section).into_iter().map(|piece| (piece, index..index + 1)));
+            } else {
+                if chunk.is_empty() {
+                    held.start = index;
+                }
+                held.end = index + 1;
+                chunk.push(section);
+                tokens += count;
+            }
+        }
+        if !chunk.is_empty() {
+            chunks.push((chunk.join("\n"), held));
+        }
+        chunks
+    }
+
+    /// Drops all but the last lines of a chunk that fit in `overlap_tokens` and returns what they hold.
+    fn keep_tail(&self, chunk: &mut VecDeque<(String, usize)>) -> usize {
+        let mut tokens = 0;
+        let keep = chunk
+            .iter()
+            .rev()
+            .take_while(|(_, count)| {
+                tokens += count;
+                tokens <= self.overlap_tokens
+            })
+            .count();
+        chunk.drain(..chunk.len() - keep);
+        chunk.iter().map(|(_, count)| count).sum()
+    }
+
+    /// The line itself when it fits a chunk next to its line break, otherwise runs of its words that do,
+    /// with their token counts. Words are counted one by one rather than re-counting the growing run.
+    fn pieces(&self, line: &str) -> Vec<(String, usize)> {
+        let line = line.trim();
+        if line.is_empty() {
+            return vec![];
+        }
+        let limit = self.chunk_tokens.saturating_sub(1).max(1);
+        let count = self.count(line);
+        if count <= limit {
+            return vec![(line.to_string(), count)];
+        }
+        let mut pieces = vec![];
+        let mut piece = String::new();
+        let mut tokens = 0;
+        for word in line.split_whitespace() {
+            for (part, count) in self.word_parts(word, limit) {
+                if tokens + count > limit && !piece.is_empty() {
+                    pieces.push((std::mem::take(&mut piece), tokens));
+                    tokens = 0;
+                }
+                if !piece.is_empty() {
+                    piece.push(' ');
+                }
+                piece.push_str(part);
+                tokens += count;
+            }
+        }
+        if !piece.is_empty() {
+            pieces.push((piece, tokens));
+        }
+        pieces
+    }
+
+    /// The word with its token count, one more for the space before it. A word that alone
+    /// exceeds `limit`, like a run of emoji or a language written without spaces, is cut between
+    /// characters into runs of fewer bytes than the limit: a token covers at least one byte.
+    fn word_parts<'a>(&self, word: &'a str, limit: usize) -> Vec<(&'a str, usize)> {
+        let count = self.count(word) + 1;
+        if count <= limit {
+            return vec![(word, count)];
+        }
+        // A character takes up to four bytes, a smaller run would not move forward
+        let max_bytes = (limit - 1).max(4);
+        let mut parts = vec![];
+        let mut start = 0;
+        for (index, ch) in word.char_indices() {
+            if index + ch.len_utf8() - start > max_bytes && index > start {
+                parts.push(&word[start..index]);
+                start = index;
+            }
+        }
+        parts.push(&word[start..]);
+        parts.into_iter().map(|part| (part, self.count(part) + 1)).collect()
+    }
+}
+
+fn join(lines: &VecDeque<(String, usize)>) -> String {
+    lines.iter().map(|(line, _)| line.as_str()).collect::<Vec<_>>().join("\n")
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+
+/// Why a video could not be turned into a list of books.
+/// Cloneable so a single failure reaches everyone waiting for the same video.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExtractError {
+    /// No subtitles in any of the languages tried.
+    NoSubtitles,
+    /// Private, age-gated, members-only or removed.
+    Unavailable,
+    TooLong { minutes: u64, limit: u64 },
+    /// A stream that is on air or has not started.
+    Live,
+    /// yt-dlp did not finish in time and was killed.
+    Timeout,
+    /// The LLM provider failed on every chunk.
+    LlmUnavailable,
+    /// Our own or the provider's rate limit was hit on every chunk.
+    RateLimited,
+    Cancelled,
+    Internal(String),
+}
+
+impl ExtractError {
+    /// Short stable name used in logs and metrics.
+    pub fn label(&self) -> &'static str {
+        match self {
+            ExtractError::NoSubtitles => "no_subtitles",
+            ExtractError::Unavailable => "unavailable",
+            ExtractError::TooLong { .. } => "too_long",
+            ExtractError::Live => "live",
+            ExtractError::Timeout => "timeout",
+            ExtractError::LlmUnavailable => "llm_unavailable",
+            ExtractError::RateLimited => "rate_limited",
+            ExtractError::Cancelled => "cancelled",
+            ExtractError::Internal(_) => "internal",
+        }
+    }
+
+    /// Classifies a failed yt-dlp run by what it printed to stderr.
+    pub fn from_yt_dlp(stderr: &str) -> Self {
+        const UNAVAILABLE: [&str; 6] = [
+            "Private video",
+            "Sign in to confirm your age",
+            "age-restricted",
+            "members-only",
+            "Video unavailable",
+            "This video has been removed",
+        ];
+        if UNAVAILABLE.iter().any(|marker| stderr.contains(marker)) {
+            ExtractError::Unavailable
+        } else {
+            let last_line = stderr.lines().rev().find(|line| !line.trim().is_empty()).unwrap_or_default();
+            ExtractError::Internal(format!("yt-dlp failed: {}", last_line.trim()))
+        }
+    }
+}
+
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtractError::TooLong { minutes, limit } => write!(f, "video is {} minutes long, limit is {}", minutes, limit),
+            ExtractError::Internal(message) => write!(f, "internal error: {}", message),
+            other => write!(f, "{}", other.label()),
+        }
+    }
+}
+
+impl std::error::Error for ExtractError {}
+
+impl From<std::io::Error> for ExtractError {
+    fn from(err: std::io::Error) -> Self {
+        ExtractError::Internal(err.to_string())
+    }
+}
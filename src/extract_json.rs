@@ -1,226 +1,471 @@
 use futures::future::join_all;
-use regex::Regex;
+use tokio::sync::Semaphore;
 use tokio::task;
-use openai::{
-    chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole}, set_key, OpenAiError,
-};
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
-use std::{error::Error, fs, str, sync::Arc};
+use std::{str, sync::{atomic::{AtomicUsize, Ordering}, Arc}};
 
+use crate::error::ExtractError;
+use crate::llm::{LlmError, LlmProvider};
+use crate::progress::{Progress, Stage};
 use crate::rate_limiter;
+use crate::chunks::Chunker;
+use crate::subtitles::{self, Cue};
+use crate::ytdlp::Chapter;
 
 
 
 
-#[derive(Debug, serde::Deserialize, Clone,PartialEq)]
+/// Words of the transcript kept on each side of a mention as its excerpt.
+const EXCERPT_WORDS: usize = 20;
+
+/// Bump whenever the prompt changes so cached results produced by the old one are not reused.
+pub const PROMPT_VERSION: u32 = 1;
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone,PartialEq)]
 pub struct Book {
     pub author: String,
-    pub title: String
+    pub title: String,
+    /// Seconds from the start of the video to every mention of the title found in the subtitles.
+    /// Results stored before mentions were tracked have the first one under `time`.
+    #[serde(default, alias = "time", deserialize_with = "mention_times")]
+    pub mentions: Vec<u64>,
+    /// What was said around the first mention.
+    #[serde(default)]
+    pub excerpt: Option<String>,
+    /// The chapter of the video the first mention falls in, or the one of the chunk the book
+    /// was found in when the title is not in the subtitles.
+    #[serde(default)]
+    pub chapter: Option<BookChapter>,
 }
 
+/// A chapter of the video as remembered with a book.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq)]
+pub struct BookChapter {
+    pub title: String,
+    /// Seconds from the start of the video.
+    pub start: u64,
+}
 
+/// Books found in a video.
+pub struct Extracted {
+    pub books: Vec<Book>,
+    /// Every chunk was answered. A list missing chunks lost to an outage is not worth caching.
+    pub complete: bool,
+}
 
+/// The subtitles of a video and its chapters, what the books are extracted from.
+pub struct VideoText<'a> {
+    pub cues: &'a [Cue],
+    /// As yt-dlp lists them, in order; empty when the video has none.
+    pub chapters: &'a [Chapter],
+    /// Auto-captions, typed in word by word and repeated as they roll. Subtitles written by
+    /// people are read as they are, they repeat words only when the speaker does.
+    pub rolling: bool,
+}
 
-pub async fn extract_json(file_name: &str, oai_key: &str, rate_limiter:&rate_limiter::RateLimiterWrapper) -> Result<Vec<Book>, Box<dyn Error>> {
-    // Prepare the prompt template
-    let prompt = r#"I will give you a paragraph of text. Read it and find the mentioned books and their authors.
-    Please return a JSON response in the following format:
-    [{"author": "string","title": "string"}]
-    Make sure the response is only valid JSON with no additional formatting like code blocks, special chars like new lines.
-    If nothing is found, then give me an empty array like []. Keep the original language for the book titles and authors."#;
-    
-    set_key(oai_key.to_owned());
-    
-    let mut content = fs::read_to_string(format!("./tmp/{}", file_name))?;
-    fs::remove_file(format!("./tmp/{}", file_name))?;
-    
-    // Regex replacements to clean up the content
-    let re_newline = Regex::new(r"\r\n")?;
-    let re_timestamps = Regex::new(r"^[\d:,\s\->]+$")?;
-    let re_empty_lines = Regex::new(r"^\s*$[\r\n]")?;
-    
-    content = re_newline.replace_all(&content, "\n").into_owned();
-    content = re_timestamps.replace_all(&content, "").into_owned();
-    content = re_empty_lines.replace_all(&content, "").into_owned();
-    content = re_newline.replace_all(&content, "\n").into_owned();
-
-    let lines: Vec<&str> = content.split('\n').collect();
-    let mut new_lines: Vec<String> = Vec::new();
-    let mut i: isize = -1;
-    
-    for mut line in lines {
-        line = line.trim();
-        if line.is_empty() {
-            continue;
+/// Index of the chapter playing at `seconds`, the last one started by then.
+fn chapter_at(chapters: &[Chapter], seconds: f64) -> Option<usize> {
+    chapters.iter().rposition(|chapter| chapter.start_time <= seconds)
+}
+
+/// The chapter at `index` as remembered with a book.
+fn book_chapter(chapters: &[Chapter], index: usize) -> Option<BookChapter> {
+    let chapter = chapters.get(index)?;
+    Some(BookChapter { title: chapter.title.clone(), start: chapter.start_time as u64 })
+}
+
+/// The transcript's lines grouped by the chapter they are said in, the whole transcript when there are no chapters.
+fn sections(cues: &[Cue], chapters: &[Chapter]) -> Vec<String> {
+    let mut sections: Vec<Vec<&str>> = vec![vec![]; chapters.len().max(1)];
+    for cue in cues {
+        // Speech before the first chapter goes with it
+        let chapter = chapter_at(chapters, cue.start.as_secs_f64()).unwrap_or(0);
+        sections[chapter].push(&cue.text);
+    }
+    sections.into_iter().map(|lines| lines.join("\n")).collect()
+}
+
+fn mention_times<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<u64>, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Times {
+        One(Option<u64>),
+        Many(Vec<u64>),
+    }
+    Ok(match serde::Deserialize::deserialize(deserializer)? {
+        Times::One(time) => time.into_iter().collect(),
+        Times::Many(times) => times,
+    })
+}
+
+/// The subtitles as one sequence of words to search, so titles split between cues are found too.
+struct Transcript<'a> {
+    /// Every word as spoken, with the start of its cue in seconds.
+    words: Vec<(&'a str, u64)>,
+    /// The words `normalize`d, those holding punctuation like "it's" split in two,
+    /// each with the index of the word it comes from.
+    tokens: Vec<(String, usize)>,
+}
+
+impl<'a> Transcript<'a> {
+    fn new(cues: &'a [Cue]) -> Self {
+        let mut transcript = Transcript { words: vec![], tokens: vec![] };
+        for cue in cues {
+            for word in cue.text.split_whitespace() {
+                let index = transcript.words.len();
+                transcript.words.push((word, cue.start.as_secs()));
+                transcript.tokens.extend(normalize(word).split(' ').filter(|token| !token.is_empty()).map(|token| (token.to_string(), index)));
+            }
         }
-        if i == -1 {
-            new_lines.push(line.to_string());
-            i += 1;
-            continue;
+        transcript
+    }
+
+    /// First and last word of every mention of the title. Titles and speech are compared
+    /// as whole normalised words, so "It" is not found in "with" and punctuation does not matter.
+    fn find(&self, title: &str) -> Vec<(usize, usize)> {
+        let title = normalize(title);
+        let title: Vec<&str> = title.split(' ').filter(|token| !token.is_empty()).collect();
+        if title.is_empty() {
+            return vec![];
         }
-        if new_lines[i as usize] == line {
-            continue;
+        self.tokens
+            .windows(title.len())
+            .filter(|window| window.iter().zip(&title).all(|((token, _), expected)| token == expected))
+            .map(|window| (window[0].1, window[window.len() - 1].1))
+            .collect()
+    }
+
+    /// Starts of the cues mentioning the title, several mentions within one second count once.
+    fn mentions(&self, title: &str) -> Vec<u64> {
+        let mut times = vec![];
+        for (word, _) in self.find(title) {
+            let time = self.words[word].1;
+            if times.last() != Some(&time) {
+                times.push(time);
+            }
         }
-        new_lines.push(line.to_string());
-        i += 1;
+        times
     }
 
-    let mut bucket = new_lines.join("\n");
-    let mut remaining = bucket.chars().count();
-    let win_size = 16000;
+    /// The words around the first mention of the title, as they were said.
+    fn excerpt(&self, title: &str) -> Option<String> {
+        let (first, last) = *self.find(title).first()?;
+        let from = first.saturating_sub(EXCERPT_WORDS);
+        let to = (last + 1 + EXCERPT_WORDS).min(self.words.len());
+        let mut excerpt = self.words[from..to].iter().map(|(word, _)| *word).collect::<Vec<_>>().join(" ");
+        if from > 0 {
+            excerpt.insert_str(0, "… ");
+        }
+        if to < self.words.len() {
+            excerpt.push_str(" …");
+        }
+        Some(excerpt)
+    }
+}
 
-    let mut i_chunk = 0;
-    let mut tasks = vec![];  // Store all async tasks for parallel execution
-    log::info!("Total chars.count: {}", remaining);
-    while remaining > 0 {
-  
-        //let mut chunk = bucket.chars().take(win_size.min(remaining)).collect::<String>();
-        let mut chunk = Arc::new(bucket.chars().take(win_size.min(remaining)).collect::<String>());
-        bucket = bucket.chars().skip(chunk.chars().count()).collect::<String>();
-        remaining = bucket.chars().count();
-        log::info!("Remaining chars.count: {}", remaining);
-
-        if remaining > 0 {
-        // Ensure the chunk ends with space or newline
-            if  ![32, 10].contains(chunk.as_bytes().last().unwrap()) && ![32,10].contains(bucket.char_indices().nth(0).map(|(_, ch)| ch.to_string()).unwrap().as_bytes().first().unwrap()) {
-                if let Some(space_index) = chunk.rfind(' ') {
-                    if space_index + 1 < chunk.len() {
-                        bucket = format!("{}{}", &chunk[space_index..], bucket);
-                        let str_chunk = chunk.as_ref(); // Get a reference to the string
-                        chunk = Arc::new(str_chunk[..space_index].to_string());
-                        
+/// Letters and digits only, lowercased, so "Thinking, Fast and Slow" matches "thinking fast and slow".
+fn normalize(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// The same title as far as the merge is concerned. A title cut at the end of one chunk may be
+/// the start of the full title found in the next: `cut_chunk` is the normalised text of the chunk
+/// `known` was found in when it is the one before `title`'s. Only a chunk ending partway through
+/// `title` cuts it, "Harry Potter" said in full is another book than "Harry Potter and the Chamber of Secrets".
+fn same_title(known: &str, title: &str, cut_chunk: Option<&str>) -> bool {
+    if known == title {
+        return true;
+    }
+    let Some(chunk) = cut_chunk else {
+        return false;
+    };
+    let words: Vec<&str> = title.split(' ').collect();
+    let known_words = known.split(' ').count();
+    known_words >= 2
+        && title.starts_with(&format!("{} ", known))
+        && (known_words..words.len()).map(|end| words[..end].join(" ")).any(|said| chunk == said || chunk.ends_with(&format!(" {}", said)))
+}
+
+/// Joins the answers for all chunks into one list, sorted by title, with each title once
+/// and every distinct author named for it. `chunks` are the texts the answers are for.
+fn merge_books(answers: Vec<Vec<Book>>, chunks: &[&str]) -> Vec<Book> {
+    let chunks: Vec<String> = chunks.iter().map(|chunk| normalize(chunk)).collect();
+    // Normalised title and authors, and the last chunk the book was found in
+    let mut found: Vec<(Book, String, Vec<String>, usize)> = vec![];
+    for (chunk, books) in answers.into_iter().enumerate() {
+        for book in books {
+            let title = normalize(&book.title);
+            let author = normalize(&book.author);
+            if title.is_empty() {
+                continue;
+            }
+            let same = found.iter_mut().find(|(_, known, _, last)| {
+                let cut_chunk = (*last + 1 == chunk).then(|| chunks[*last].as_str());
+                same_title(known, &title, cut_chunk)
+            });
+            match same {
+                Some((merged, known, authors, last)) => {
+                    if title.len() > known.len() {
+                        merged.title = book.title;
+                        *known = title;
+                    }
+                    if !author.is_empty() && !authors.contains(&author) {
+                        authors.push(author);
+                        merged.author = if merged.author.is_empty() { book.author } else { format!("{}, {}", merged.author, book.author) };
                     }
+                    *last = chunk;
                 }
+                None => found.push((book, title, vec![author], chunk)),
             }
         }
-        remaining = bucket.chars().count();
-        log::info!("Remaining chars.count: {}", remaining);
-        log::info!("Chunk {} chars.count {}\n\n", i_chunk, chunk.chars().count());
-        let messages = [
-            ChatCompletionMessage {
-                role: ChatCompletionMessageRole::System,
-                content: Some(prompt.to_string()),
-                name: None,
-                function_call: None,
-            },
-            ChatCompletionMessage {
-                role: ChatCompletionMessageRole::User,
-                content: Some(Arc::try_unwrap(chunk.clone()).unwrap_or_else(|s| s.clone().to_string())),
-                name: None,
-                function_call: None,
-            },
-        ];
+    }
+    let mut books: Vec<Book> = found.into_iter().map(|(book, ..)| book).collect();
+    books.sort_by(|a, b| a.title.cmp(&b.title));
+    books
+}
+
+
+
+
+pub async fn extract_json(video: VideoText<'_>, llm: &Arc<dyn LlmProvider>, chunker: &Chunker, rate_limiter:&rate_limiter::RateLimiterWrapper, llm_slots: &Arc<Semaphore>, progress: &Progress, cancel: &CancellationToken) -> Result<Extracted, ExtractError> {
+    // Prepare the prompt template
+    let prompt = r#"I will give you a paragraph of text. Read it and find the mentioned books and their authors.
+    Please return a JSON response in the following format:
+    [{"author": "string","title": "string"}]
+    Make sure the response is only valid JSON with no additional formatting like code blocks, special chars like new lines.
+    If nothing is found, then give me an empty array like []. Keep the original language for the book titles and authors."#;
+
+    let cues = video.cues;
+    let merged = if video.rolling { subtitles::merge_rolling(cues) } else { cues.to_vec() };
+    let words = |cues: &[Cue]| cues.iter().map(|cue| cue.text.split_whitespace().count()).sum::<usize>();
+    if video.rolling {
+        log::info!("Merged {} cues into {}, {} words left of {}", cues.len(), merged.len(), words(&merged), words(cues));
+    }
+    let transcript = Transcript::new(&merged);
+
+    // Chapters change the topic, so chunks are cut between them where the size allows
+    let sections = sections(&merged, video.chapters);
+    let (chunks, chunk_sections): (Vec<Arc<String>>, Vec<_>) =
+        chunker.split_sections(&sections).into_iter().map(|(chunk, held)| (Arc::new(chunk), held)).unzip();
+    let prompt_tokens = chunker.count(prompt);
+    log::info!(
+        "Split {} transcript tokens in {} chapters into {} chunks of up to {}",
+        sections.iter().map(|section| chunker.count(section)).sum::<usize>(),
+        video.chapters.len(),
+        chunks.len(),
+        chunker.chunk_tokens,
+    );
+
+    // Chunks are cut up front so every task knows the total it reports progress against
+    let total = chunks.len();
+    let done = Arc::new(AtomicUsize::new(0));
+    let mut tasks = vec![];  // Store all async tasks for parallel execution
+    for (i_chunk, chunk) in chunks.iter().cloned().enumerate() {
+        let llm_clone = Arc::clone(llm);
         let rate_limiter_clone = rate_limiter.clone(); // Clone the Arc
-        let chunk_clone = Arc::clone(&chunk);
+        // The answer can take up to the reserve, so it is paid for up front and refunded once the usage is known
+        let tokens = prompt_tokens + chunker.count(&chunk) + chunker.output_reserve;
+        let output_reserve = chunker.output_reserve as u64;
+        let llm_slots_clone = Arc::clone(llm_slots);
+        let done_clone = Arc::clone(&done);
+        let progress_clone = progress.clone();
         let task  = task::spawn(async move {
             let task_id = format!("{}_chunk_{}",Uuid::new_v4(), i_chunk);
             log::info!("{} Launching task", task_id);
-            let tokens = (2 * chunk_clone.chars().count() + 2 * prompt.chars().count())/4;
+            // Held until the completion returns, bounds concurrent LLM calls across all jobs
+            let _slot = llm_slots_clone.acquire_owned().await.expect("LLM semaphore closed");
             let allowed = rate_limiter_clone.is_allowed(tokens, &task_id.to_string()).await;
-            if allowed {
+            let res = if allowed {
                 log::info!("{} Task allowed, run", task_id);
-                match ChatCompletion::builder("gpt-4o-mini", messages.clone())
-                    .temperature(0.7)
-                    .create()
-                    .await
+                let reservation = rate_limiter_clone.reserve(tokens, &task_id);
+                let completion = llm_clone.complete(prompt, &chunk, output_reserve).await;
+                match completion.as_ref().ok().and_then(|completion| completion.total_tokens) {
+                    Some(used) => reservation.settle(used).await,
+                    None => reservation.commit(),
+                }
+                match completion
                 {
-                    Ok(chat_completion) => {                
+                    Ok(completion) => {
                         log::info!("{} Task completed", task_id);
-                        Ok::<_, Box<dyn Error + Send>>(chat_completion)
+                        Ok(completion)
                     },
+                    Err(LlmError::RateLimited(e)) => {
+                        log::error!("{} Task rate limited by {}: {}", task_id, llm_clone.model(), e);
+                        Err(ExtractError::RateLimited)
+                    }
                     Err(e) => {
                         log::error!("{} Task errored: {}",task_id, e);
-                        Err(Box::new(e) as Box<dyn Error + Send>)
+                        Err(ExtractError::LlmUnavailable)
                     }
                 }
             } else {
                 log::error!("{} Task not allowed due rate limit", task_id);
-                Err(Box::new(OpenAiError { message: "Rate limit exceeded".to_string(), error_type: "RateError".to_string(), param: None, code: None }) as Box<dyn Error + Send>)
-
-            }
+                Err(ExtractError::RateLimited)
+            };
+            let finished = done_clone.fetch_add(1, Ordering::SeqCst) + 1;
+            progress_clone.report(Stage::Analysing { done: finished, total });
+            res
         });
         tasks.push(task);  // Collect the task
-        i_chunk += 1;
     }
-    let responses = join_all(tasks).await;
+    let abort_handles: Vec<_> = tasks.iter().map(|task| task.abort_handle()).collect();
+    let responses = tokio::select! {
+        responses = join_all(tasks) => responses,
+        _ = cancel.cancelled() => {
+            // Dropping a task's reservation hands its rate-limit budget back
+            for handle in abort_handles {
+                handle.abort();
+            }
+            log::info!("Extraction cancelled, {} chunk tasks aborted", total);
+            return Err(ExtractError::Cancelled);
+        }
+    };
+    progress.report(Stage::Merging);
+    // A few failed chunks still leave a useful answer, all of them failing does not.
+    // Only answers that parse count, an unreadable one lost the chunk's books just the same
+    let mut answered = 0;
+    let mut failure = None;
+    // One list per chunk in chunk order, the merge needs to know which chunks are neighbours
     let mut books: Vec<Vec<Book>> = Vec::new();
-    for res in responses {
+    for (res, held) in responses.into_iter().zip(chunk_sections) {
+        let mut chunk_books = vec![];
         match res {
             Ok(response) => { // Handle the Ok case
                 match response {
-                    Ok(chat_completion) => {
-                        for choice in chat_completion.choices {
-                            if choice.message.role == ChatCompletionMessageRole::Assistant && choice.message.content.is_some() {
-                                let content = choice.message.content.clone().unwrap();
-                                log::info!("Raw response: {}", content);
-                    
-                                let trimmed_content = content.trim();
-                                match serde_json::from_str::<Vec<Book>>(trimmed_content) {
-                                    Ok(parsed_books) => {
-                                        if !parsed_books.is_empty() {
-                                            books.push(parsed_books);
-                                        }
-                                    }
-                                    Err(err) => {
-                                        log::error!("Failed to parse JSON: {}", err);
-                                    }
-                                }
+                    Ok(completion) => {
+                        log::info!("Raw response: {}", completion.content);
+                        match serde_json::from_str::<Vec<Book>>(completion.content.trim()) {
+                            Ok(parsed_books) => {
+                                answered += 1;
+                                // A title the subtitles spell differently is never found in them,
+                                // such books keep the chapter of their chunk if it lies in one
+                                let chapter = if held.len() == 1 { book_chapter(video.chapters, held.start) } else { None };
+                                chunk_books.extend(parsed_books.into_iter().map(|book| Book { chapter: chapter.clone(), ..book }));
+                            }
+                            Err(err) => {
+                                log::error!("Failed to parse JSON: {}", err);
                             }
                         }
                     }
                     Err(e) => {
                         log::error!("Failed to get chat completion: {}", e);
+                        // Being rate limited is worth telling apart, the user can simply retry later
+                        if failure != Some(ExtractError::RateLimited) {
+                            failure = Some(e);
+                        }
                     }
                 }
             },
             Err(e) => {
                 log::error!("Error in task: {}", e);
+                failure.get_or_insert(ExtractError::Internal(e.to_string()));
             }
         }
+        books.push(chunk_books);
+    }
+    if answered == 0 {
+        if let Some(err) = failure {
+            return Err(err);
+        }
     }
     
     
-    let mut res: Vec<Book> = books
-    .into_iter()
-    .filter(|x| !x.is_empty())
-    .flatten()
-    .collect::<Vec<Book>>();
-
-    res.sort_by(|a, b| a.title.cmp(&b.title));
-    log::info!("Books found: {:#?}", res);
-
-    let res_clone = res.clone();
-    let res_clone2 = res.clone();
-
-    // Deduplicate the books
-    res = res_clone2
-        .into_iter()
-        .fold(Vec::new(), |mut acc, x| {
-            if !acc.contains(&x) {
-                acc.push(x);
-            }
-            acc
-        })
+    log::info!("Books found: {:#?}", books);
+    let texts: Vec<&str> = chunks.iter().map(|chunk| chunk.as_str()).collect();
+    let mut res: Vec<Book> = merge_books(books, &texts)
         .into_iter()
         .map(|book| {
-            let combined_authors = res_clone
-                .iter()
-                .filter(|t| t.title == book.title)
-                .map(|m| m.author.clone())
-                .collect::<Vec<String>>()
-                .join(", ");
+            let mentions = transcript.mentions(&book.title);
+            let chapter = mentions
+                .first()
+                .and_then(|&time| chapter_at(video.chapters, time as f64))
+                .and_then(|index| book_chapter(video.chapters, index))
+                .or(book.chapter);
             Book {
-                author: combined_authors,
-                title: book.title.clone(),
+                excerpt: transcript.excerpt(&book.title),
+                mentions,
+                chapter,
+                ..book
             }
         })
-        .collect::<Vec<Book>>()
-        .into_iter()
         .filter(|x| x.author != x.title) // Ensure final filtering happens here
-        .collect::<Vec<Book>>();
+        .collect();
+    // Grouped by chapter in the order they play, books without one first; stable, so titles stay sorted within
+    res.sort_by_key(|book| book.chapter.as_ref().map(|chapter| chapter.start));
 
     log::info!("Final books: {:#?}", res);
+    if answered < total {
+        log::info!("Only {} of {} chunks answered", answered, total);
+    }
 
     //todo revalidate the books with chatgpt
-    Ok(res)
-}
\ No newline at end of file
+    Ok(Extracted { books: res, complete: answered == total })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn cue(start: u64, text: &str) -> Cue {
+        Cue { start: Duration::from_secs(start), end: Duration::from_secs(start + 2), text: text.to_string() }
+    }
+
+    fn book(author: &str, title: &str) -> Book {
+        Book { author: author.to_string(), title: title.to_string(), mentions: vec![], excerpt: None, chapter: None }
+    }
+
+    fn titles(books: &[Book]) -> Vec<&str> {
+        books.iter().map(|book| book.title.as_str()).collect()
+    }
+
+    #[test]
+    fn merges_a_title_cut_at_the_end_of_a_chunk() {
+        let chunks = ["my favourite is Harry Potter and the", "Harry Potter and the Chamber of Secrets, no doubt"];
+        let answers = vec![vec![book("Rowling", "Harry Potter")], vec![book("J. K. Rowling", "Harry Potter and the Chamber of Secrets")]];
+        let merged = merge_books(answers, &chunks);
+        assert_eq!(titles(&merged), vec!["Harry Potter and the Chamber of Secrets"]);
+        assert_eq!(merged[0].author, "Rowling, J. K. Rowling");
+    }
+
+    #[test]
+    fn merges_a_title_found_again_chunks_later() {
+        let chunks = ["Dune changed my life", "nothing about books here", "back to dune, by Herbert"];
+        let answers = vec![vec![book("Frank Herbert", "Dune")], vec![], vec![book("Frank Herbert", "dune")]];
+        let merged = merge_books(answers, &chunks);
+        assert_eq!(titles(&merged), vec!["Dune"]);
+        assert_eq!(merged[0].author, "Frank Herbert");
+    }
+
+    #[test]
+    fn keeps_a_title_said_in_full_apart_from_a_longer_one() {
+        let chunks = ["I grew up on Harry Potter, all of it", "Harry Potter and the Chamber of Secrets is the best one"];
+        let answers = vec![vec![book("Rowling", "Harry Potter")], vec![book("Rowling", "Harry Potter and the Chamber of Secrets")]];
+        let merged = merge_books(answers, &chunks);
+        assert_eq!(titles(&merged), vec!["Harry Potter", "Harry Potter and the Chamber of Secrets"]);
+    }
+
+    #[test]
+    fn finds_titles_as_whole_words() {
+        let cues = [cue(1, "we went with it"), cue(3, "and then It came out")];
+        let transcript = Transcript::new(&cues);
+        assert_eq!(transcript.mentions("It"), vec![1, 3]);
+        assert_eq!(transcript.mentions("Wit"), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn finds_punctuated_titles_in_auto_captions() {
+        let cues = [cue(5, "i loved thinking fast"), cue(7, "and slow by kahneman")];
+        let transcript = Transcript::new(&cues);
+        assert_eq!(transcript.mentions("Thinking, Fast and Slow"), vec![5]);
+        assert_eq!(transcript.excerpt("Thinking, Fast and Slow").unwrap(), "i loved thinking fast and slow by kahneman");
+    }
+}
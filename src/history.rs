@@ -0,0 +1,216 @@
+use std::error::Error;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::DateTime;
+use rusqlite::{params, Connection, OptionalExtension};
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+
+use crate::extract_json::Book;
+use crate::i18n::Lang;
+
+const CALLBACK_PREFIX: &str = "history:";
+/// Why? buttons under one message of a list, the books after them go without.
+const WHY_BUTTONS: usize = 30;
+
+/// Jobs shown per /history page.
+pub const PAGE_SIZE: usize = 5;
+
+/// A completed job as remembered for the user.
+pub struct HistoryEntry {
+    pub id: i64,
+    pub video_id: String,
+    pub title: String,
+    pub lang: String,
+    /// Unix time the job completed at.
+    pub created_at: i64,
+    pub books: Vec<Book>,
+}
+
+/// A history menu button press.
+pub enum HistoryAction {
+    /// A page of the history of the user who opened the menu, named first.
+    Page(u64, usize),
+    Open(i64),
+    /// The excerpt of a book in an entry, by its index in the entry's list.
+    Why(i64, usize),
+}
+
+/// Completed jobs per Telegram user, so past results can be shown again without re-running the extraction.
+pub struct History {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Clone for History {
+    fn clone(&self) -> Self {
+        History {
+            conn: Arc::clone(&self.conn),
+        }
+    }
+}
+
+fn entry_from_row(row: &rusqlite::Row) -> rusqlite::Result<(i64, String, String, String, i64, String)> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))
+}
+
+impl History {
+    pub fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER NOT NULL,
+                chat_id INTEGER NOT NULL,
+                video_id TEXT NOT NULL,
+                title TEXT NOT NULL,
+                lang TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                books TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS history_user ON history (user_id, id);",
+        )?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Stores a completed job, with the chat its list was sent to, and returns the ID of its entry.
+    pub fn record(&self, user_id: u64, chat_id: i64, video_id: &str, title: &str, lang: &str, books: &[Book]) -> Result<i64, Box<dyn Error>> {
+        let json = serde_json::to_string(books)?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT INTO history (user_id, chat_id, video_id, title, lang, created_at, books) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![user_id as i64, chat_id, video_id, title, lang, now, json],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// The user's jobs on the given page, newest first, and whether older ones exist.
+    pub fn page(&self, user_id: u64, page: usize) -> Result<(Vec<HistoryEntry>, bool), Box<dyn Error>> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let mut stmt = conn.prepare(
+            "SELECT id, video_id, title, lang, created_at, books FROM history
+             WHERE user_id = ?1 ORDER BY id DESC LIMIT ?2 OFFSET ?3",
+        )?;
+        // One extra row tells whether there is a next page
+        let rows = stmt
+            .query_map(
+                params![user_id as i64, (PAGE_SIZE + 1) as i64, (page * PAGE_SIZE) as i64],
+                entry_from_row,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        let has_more = rows.len() > PAGE_SIZE;
+        let mut entries = Vec::new();
+        for (id, video_id, title, lang, created_at, books) in rows.into_iter().take(PAGE_SIZE) {
+            entries.push(HistoryEntry {
+                id,
+                video_id,
+                title,
+                lang,
+                created_at,
+                books: serde_json::from_str(&books)?,
+            });
+        }
+        Ok((entries, has_more))
+    }
+
+    /// A single job, only if it belongs to the user.
+    pub fn get(&self, user_id: u64, id: i64) -> Result<Option<HistoryEntry>, Box<dyn Error>> {
+        self.find("user_id = ?1 AND id = ?2", params![user_id as i64, id])
+    }
+
+    /// A single job of the user or one whose list was sent to the chat, so everyone in a group
+    /// can press the Why? buttons under a list.
+    pub fn get_in_chat(&self, user_id: u64, chat_id: i64, id: i64) -> Result<Option<HistoryEntry>, Box<dyn Error>> {
+        self.find("(user_id = ?1 OR chat_id = ?2) AND id = ?3", params![user_id as i64, chat_id, id])
+    }
+
+    fn find(&self, filter: &str, params: impl rusqlite::Params) -> Result<Option<HistoryEntry>, Box<dyn Error>> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let row = conn
+            .query_row(
+                &format!("SELECT id, video_id, title, lang, created_at, books FROM history WHERE {}", filter),
+                params,
+                entry_from_row,
+            )
+            .optional()?;
+        match row {
+            Some((id, video_id, title, lang, created_at, books)) => Ok(Some(HistoryEntry {
+                id,
+                video_id,
+                title,
+                lang,
+                created_at,
+                books: serde_json::from_str(&books)?,
+            })),
+            None => Ok(None),
+        }
+    }
+}
+
+fn short_date(created_at: i64) -> String {
+    DateTime::from_timestamp(created_at, 0)
+        .map(|date| date.format("%Y-%m-%d").to_string())
+        .unwrap_or_default()
+}
+
+/// The entries of one page of the user's history, with buttons to the pages around it.
+pub fn keyboard(entries: &[HistoryEntry], user_id: u64, page: usize, has_more: bool, lang: Lang) -> InlineKeyboardMarkup {
+    let mut rows: Vec<Vec<InlineKeyboardButton>> = entries
+        .iter()
+        .map(|entry| {
+            let title: String = entry.title.chars().take(40).collect();
+            vec![InlineKeyboardButton::callback(
+                format!("{} · {}", short_date(entry.created_at), title),
+                format!("{}open:{}", CALLBACK_PREFIX, entry.id),
+            )]
+        })
+        .collect();
+    let mut nav = Vec::new();
+    if page > 0 {
+        nav.push(InlineKeyboardButton::callback(lang.newer(), format!("{}page:{}:{}", CALLBACK_PREFIX, user_id, page - 1)));
+    }
+    if has_more {
+        nav.push(InlineKeyboardButton::callback(lang.older(), format!("{}page:{}:{}", CALLBACK_PREFIX, user_id, page + 1)));
+    }
+    if !nav.is_empty() {
+        rows.push(nav);
+    }
+    InlineKeyboardMarkup::new(rows)
+}
+
+/// A Why? button for each of the `shown` books of the entry that has an excerpt, none if no book has one.
+pub fn why_keyboard(entry_id: i64, books: &[Book], shown: Range<usize>, lang: Lang) -> Option<InlineKeyboardMarkup> {
+    let rows: Vec<Vec<InlineKeyboardButton>> = books
+        .iter()
+        .enumerate()
+        .skip(shown.start)
+        .take(shown.len())
+        .filter(|(_, book)| book.excerpt.is_some())
+        .take(WHY_BUTTONS)
+        .map(|(index, book)| {
+            let title: String = book.title.chars().take(40).collect();
+            vec![InlineKeyboardButton::callback(
+                lang.why_button(&title),
+                format!("{}why:{}:{}", CALLBACK_PREFIX, entry_id, index),
+            )]
+        })
+        .collect();
+    (!rows.is_empty()).then(|| InlineKeyboardMarkup::new(rows))
+}
+
+/// Extracts the history action from the data of a history button press.
+pub fn parse_callback(data: &str) -> Option<HistoryAction> {
+    let rest = data.strip_prefix(CALLBACK_PREFIX)?;
+    if let Some(page) = rest.strip_prefix("page:") {
+        let (user_id, page) = page.split_once(':')?;
+        return Some(HistoryAction::Page(user_id.parse().ok()?, page.parse().ok()?));
+    }
+    if let Some(why) = rest.strip_prefix("why:") {
+        let (id, index) = why.split_once(':')?;
+        return Some(HistoryAction::Why(id.parse().ok()?, index.parse().ok()?));
+    }
+    rest.strip_prefix("open:")?.parse().ok().map(HistoryAction::Open)
+}
@@ -0,0 +1,361 @@
+use teloxide::types::BotCommand;
+
+use crate::error::ExtractError;
+use crate::tracks::TrackKind;
+
+/// Language of the bot's replies; every user-facing text lives in the `impl` below.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Lang {
+    #[default]
+    En,
+    Ru,
+}
+
+/// Reply languages the catalogue covers, used to register translated command menus.
+pub const SUPPORTED: [Lang; 2] = [Lang::En, Lang::Ru];
+
+impl Lang {
+    /// The saved preference wins over the Telegram client language, English is the fallback.
+    pub fn pick(preferred: Option<&str>, client: Option<&str>) -> Lang {
+        preferred.or(client).map(Lang::from_code).unwrap_or_default()
+    }
+
+    /// Telegram sends IETF tags such as `ru` or `pt-br`, only the primary subtag matters.
+    fn from_code(code: &str) -> Lang {
+        match code.split('-').next().unwrap_or_default().to_lowercase().as_str() {
+            "ru" => Lang::Ru,
+            _ => Lang::En,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Lang::En => "en",
+            Lang::Ru => "ru",
+        }
+    }
+
+    pub fn about(&self) -> &'static str {
+        match self {
+            Lang::En => "Send me a YouTube link and get a list of the books mentioned in the video. English and Russian are supported. Other languages have yet to be appropriately tested but are available, I guess.",
+            Lang::Ru => "Пришлите ссылку на YouTube, и я найду книги, упомянутые в видео. Поддерживаются английский и русский. Другие языки тоже доступны, но пока толком не проверены.",
+        }
+    }
+
+    /// Description of a bot command, `default` is the English one from the `Command` enum.
+    fn command_description(&self, command: &str, default: &str) -> String {
+        let translated = match (self, command) {
+            (Lang::Ru, "/start") => "что умеет этот бот",
+            (Lang::Ru, "/help") => "показать эту справку",
+            (Lang::Ru, "/settings") => "ваши настройки",
+            (Lang::Ru, "/history") => "видео, которые вы уже присылали",
+            (Lang::Ru, "/lang") => "язык субтитров для видео с неизвестным языком, например /lang en; /lang none чтобы сбросить",
+            (Lang::Ru, "/format") => "как выводить книги: plain или numbered",
+            _ => default,
+        };
+        translated.to_string()
+    }
+
+    /// Command menu in this language, built from the `Command` enum so new commands show up untranslated rather than missing.
+    pub fn commands(&self, commands: Vec<BotCommand>) -> Vec<BotCommand> {
+        commands
+            .into_iter()
+            .map(|cmd| {
+                let description = self.command_description(&cmd.command, &cmd.description);
+                BotCommand::new(cmd.command.trim_start_matches('/'), description)
+            })
+            .collect()
+    }
+
+    pub fn help(&self, commands: Vec<BotCommand>) -> String {
+        let header = match self {
+            Lang::En => "These commands are supported:",
+            Lang::Ru => "Доступные команды:",
+        };
+        let lines: Vec<String> = self
+            .commands(commands)
+            .iter()
+            .map(|cmd| format!("/{} — {}", cmd.command, cmd.description))
+            .collect();
+        format!("{}\n\n{}\n{}", self.about(), header, lines.join("\n"))
+    }
+
+    pub fn not_a_link(&self) -> &'static str {
+        match self {
+            Lang::En => "Does not look like a YouTube link. Try one more time.",
+            Lang::Ru => "Это не похоже на ссылку на YouTube. Попробуйте ещё раз.",
+        }
+    }
+
+    pub fn link_received(&self) -> &'static str {
+        match self {
+            Lang::En => "Valid YouTube link received. Hold the line",
+            Lang::Ru => "Ссылка получена. Подождите немного",
+        }
+    }
+
+    pub fn queue_position(&self, position: usize) -> String {
+        match self {
+            Lang::En => format!("Valid YouTube link received. You are #{} in the queue", position),
+            Lang::Ru => format!("Ссылка получена. Вы №{} в очереди", position),
+        }
+    }
+
+    pub fn too_many_jobs(&self) -> &'static str {
+        match self {
+            Lang::En => "You already have videos in progress. Send this link again once they are done.",
+            Lang::Ru => "Ваши видео ещё обрабатываются. Пришлите эту ссылку снова, когда они будут готовы.",
+        }
+    }
+
+    pub fn cancel_button(&self) -> &'static str {
+        match self {
+            Lang::En => "Cancel",
+            Lang::Ru => "Отмена",
+        }
+    }
+
+    pub fn cancelled(&self) -> &'static str {
+        match self {
+            Lang::En => "Cancelled.",
+            Lang::Ru => "Отменено.",
+        }
+    }
+
+    pub fn nothing_to_cancel(&self) -> &'static str {
+        match self {
+            Lang::En => "Nothing to cancel",
+            Lang::Ru => "Нечего отменять",
+        }
+    }
+
+    pub fn looking_up(&self) -> &'static str {
+        match self {
+            Lang::En => "Looking up the video...",
+            Lang::Ru => "Ищу видео...",
+        }
+    }
+
+    pub fn language_detected(&self, lang: &str) -> String {
+        match self {
+            Lang::En => format!("Language detected: {}. Downloading subtitles...", lang),
+            Lang::Ru => format!("Язык видео: {}. Скачиваю субтитры...", lang),
+        }
+    }
+
+    pub fn subtitles_downloaded(&self) -> &'static str {
+        match self {
+            Lang::En => "Subtitles downloaded. Looking for books...",
+            Lang::Ru => "Субтитры скачаны. Ищу книги...",
+        }
+    }
+
+    pub fn analysing(&self, done: usize, total: usize) -> String {
+        match self {
+            Lang::En => format!("Looking for books: chunk {}/{} analysed", done, total),
+            Lang::Ru => format!("Ищу книги: обработано частей {}/{}", done, total),
+        }
+    }
+
+    pub fn merging(&self) -> &'static str {
+        match self {
+            Lang::En => "Merging results...",
+            Lang::Ru => "Собираю результаты...",
+        }
+    }
+
+    fn track_kind(&self, kind: TrackKind) -> &'static str {
+        match (self, kind) {
+            (Lang::En, TrackKind::Manual) => "by the author",
+            (Lang::En, TrackKind::Auto) => "auto-generated",
+            (Lang::En, TrackKind::Translated) => "auto-translated",
+            (Lang::Ru, TrackKind::Manual) => "авторские",
+            (Lang::Ru, TrackKind::Auto) => "автоматические",
+            (Lang::Ru, TrackKind::Translated) => "автоперевод",
+        }
+    }
+
+    pub fn pick_track(&self) -> &'static str {
+        match self {
+            Lang::En => "This video has subtitles in several languages. Which ones should I read? The first one is my guess.",
+            Lang::Ru => "У этого видео субтитры на нескольких языках. Какие читать? Первые — мой выбор.",
+        }
+    }
+
+    pub fn track_label(&self, key: &str, kind: TrackKind) -> String {
+        format!("{} · {}", key, self.track_kind(kind))
+    }
+
+    pub fn track_gone(&self) -> &'static str {
+        match self {
+            Lang::En => "This choice is no longer pending.",
+            Lang::Ru => "Этот выбор уже не актуален.",
+        }
+    }
+
+    pub fn done(&self, lang: &str, kind: TrackKind) -> String {
+        let source = self.track_kind(kind);
+        match self {
+            Lang::En => format!("Done. Subtitles: {}, {}", lang, source),
+            Lang::Ru => format!("Готово. Субтитры: {}, {}", lang, source),
+        }
+    }
+
+    pub fn failure(&self, err: &ExtractError) -> String {
+        match (self, err) {
+            (Lang::En, ExtractError::NoSubtitles) => "This video has no subtitles I could use, so I can't look for books in it.".to_string(),
+            (Lang::Ru, ExtractError::NoSubtitles) => "У этого видео нет подходящих субтитров, поэтому книги в нём не найти.".to_string(),
+            (Lang::En, ExtractError::Unavailable) => "I can't open this video: it is private, age-restricted, members-only or removed.".to_string(),
+            (Lang::Ru, ExtractError::Unavailable) => "Не могу открыть это видео: оно приватное, с возрастным ограничением, только для спонсоров или удалено.".to_string(),
+            (Lang::En, ExtractError::TooLong { minutes, limit }) => format!("This video is {} minutes long, I only handle videos up to {} minutes.", minutes, limit),
+            (Lang::Ru, ExtractError::TooLong { minutes, limit }) => format!("Это видео длится {} мин., я обрабатываю видео не длиннее {} мин.", minutes, limit),
+            (Lang::En, ExtractError::Live) => "This is a live stream. Send the link again once the stream is over.".to_string(),
+            (Lang::Ru, ExtractError::Live) => "Это прямая трансляция. Пришлите ссылку снова, когда она закончится.".to_string(),
+            (Lang::En, ExtractError::Timeout) => "YouTube is taking too long to respond. Please try again later.".to_string(),
+            (Lang::Ru, ExtractError::Timeout) => "YouTube слишком долго не отвечает. Попробуйте позже.".to_string(),
+            (Lang::En, ExtractError::LlmUnavailable) => "The text analysis service is not responding. Please try again later.".to_string(),
+            (Lang::Ru, ExtractError::LlmUnavailable) => "Сервис анализа текста не отвечает. Попробуйте позже.".to_string(),
+            (Lang::En, ExtractError::RateLimited) => "Too many requests right now. Please try again in a few minutes.".to_string(),
+            (Lang::Ru, ExtractError::RateLimited) => "Сейчас слишком много запросов. Попробуйте через несколько минут.".to_string(),
+            (_, ExtractError::Cancelled) => self.cancelled().to_string(),
+            (Lang::En, ExtractError::Internal(_)) => "Something went wrong while processing the video. Please try again later.".to_string(),
+            (Lang::Ru, ExtractError::Internal(_)) => "Что-то пошло не так при обработке видео. Попробуйте позже.".to_string(),
+        }
+    }
+
+    pub fn no_books(&self) -> &'static str {
+        match self {
+            Lang::En => "No books or authors found in the video.",
+            Lang::Ru => "В видео не нашлось ни книг, ни авторов.",
+        }
+    }
+
+    pub fn all_found(&self) -> &'static str {
+        match self {
+            Lang::En => "That's all I could find. Hope it helps!",
+            Lang::Ru => "Это всё, что удалось найти. Надеюсь, пригодится!",
+        }
+    }
+
+    pub fn lang_usage(&self) -> &'static str {
+        match self {
+            Lang::En => "Usage: /lang <language code>, e.g. /lang en or /lang none",
+            Lang::Ru => "Использование: /lang <код языка>, например /lang en или /lang none",
+        }
+    }
+
+    pub fn format_usage(&self) -> &'static str {
+        match self {
+            Lang::En => "Usage: /format plain or /format numbered",
+            Lang::Ru => "Использование: /format plain или /format numbered",
+        }
+    }
+
+    pub fn invalidate_usage(&self) -> &'static str {
+        match self {
+            Lang::En => "Usage: /invalidate <YouTube link or video ID>",
+            Lang::Ru => "Использование: /invalidate <ссылка на YouTube или ID видео>",
+        }
+    }
+
+    pub fn invalidated(&self, removed: usize, video_id: &str) -> String {
+        match self {
+            Lang::En => format!("Removed {} cached result(s) for {}", removed, video_id),
+            Lang::Ru => format!("Удалено результатов из кэша для {}: {}", video_id, removed),
+        }
+    }
+
+    pub fn history_title(&self) -> &'static str {
+        match self {
+            Lang::En => "Videos you have sent before:",
+            Lang::Ru => "Видео, которые вы уже присылали:",
+        }
+    }
+
+    pub fn history_empty(&self) -> &'static str {
+        match self {
+            Lang::En => "You have not sent any videos yet.",
+            Lang::Ru => "Вы ещё не присылали видео.",
+        }
+    }
+
+    pub fn history_gone(&self) -> &'static str {
+        match self {
+            Lang::En => "This result is no longer available.",
+            Lang::Ru => "Этот результат больше недоступен.",
+        }
+    }
+
+    pub fn why_button(&self, title: &str) -> String {
+        match self {
+            Lang::En => format!("Why? {}", title),
+            Lang::Ru => format!("Почему? {}", title),
+        }
+    }
+
+    pub fn no_excerpt(&self) -> &'static str {
+        match self {
+            Lang::En => "The subtitles never name this book, it was recognised from the context.",
+            Lang::Ru => "В субтитрах эта книга не названа, её узнали по контексту.",
+        }
+    }
+
+    pub fn more_mentions(&self, count: usize) -> String {
+        match (self, count) {
+            (Lang::En, 1) => "1 more mention".to_string(),
+            (Lang::En, _) => format!("{} more mentions", count),
+            (Lang::Ru, _) => format!("ещё упоминаний: {}", count),
+        }
+    }
+
+    pub fn history_entry(&self, title: &str, video_id: &str, lang: &str) -> String {
+        match self {
+            Lang::En => format!("{}\nhttps://youtu.be/{}\nLanguage: {}", title, video_id, lang),
+            Lang::Ru => format!("{}\nhttps://youtu.be/{}\nЯзык: {}", title, video_id, lang),
+        }
+    }
+
+    pub fn newer(&self) -> &'static str {
+        match self {
+            Lang::En => "« Newer",
+            Lang::Ru => "« Новее",
+        }
+    }
+
+    pub fn older(&self) -> &'static str {
+        match self {
+            Lang::En => "Older »",
+            Lang::Ru => "Старше »",
+        }
+    }
+
+    pub fn fallback_lang(&self, value: Option<&str>) -> String {
+        match self {
+            Lang::En => format!("Fallback subtitle language: {}", value.unwrap_or("none")),
+            Lang::Ru => format!("Запасной язык субтитров: {}", value.unwrap_or("нет")),
+        }
+    }
+
+    pub fn output_format(&self, value: &str) -> String {
+        match self {
+            Lang::En => format!("Output format: {}", value),
+            Lang::Ru => format!("Формат вывода: {}", value),
+        }
+    }
+
+    pub fn timestamps(&self, on: bool) -> String {
+        match (self, on) {
+            (Lang::En, true) => "Timestamps: on".to_string(),
+            (Lang::En, false) => "Timestamps: off".to_string(),
+            (Lang::Ru, true) => "Время упоминания: вкл".to_string(),
+            (Lang::Ru, false) => "Время упоминания: выкл".to_string(),
+        }
+    }
+
+    pub fn reply_lang(&self, value: Option<&str>) -> String {
+        match self {
+            Lang::En => format!("Reply language: {}", value.unwrap_or("auto")),
+            Lang::Ru => format!("Язык ответов: {}", value.unwrap_or("авто")),
+        }
+    }
+}
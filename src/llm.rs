@@ -0,0 +1,153 @@
+use std::env;
+use std::fmt;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use openai::{
+    chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole}, set_base_url, set_key, OpenAiError,
+};
+
+use crate::extract_json::Book;
+
+/// What the model answered, with the tokens the request cost when the provider reports them.
+pub struct Completion {
+    pub content: String,
+    pub total_tokens: Option<usize>,
+}
+
+/// Why a completion failed, the message is for the logs.
+pub enum LlmError {
+    /// The provider refused the request for being over its rate limit or quota.
+    RateLimited(String),
+    Unavailable(String),
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LlmError::RateLimited(message) | LlmError::Unavailable(message) => f.write_str(message),
+        }
+    }
+}
+
+/// A chat model the books are extracted with.
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    /// Also picks the tokenizer chunks are measured with.
+    fn model(&self) -> &str;
+
+    /// Answers the system `prompt` about `text` in at most `max_tokens` tokens.
+    async fn complete(&self, prompt: &str, text: &str, max_tokens: u64) -> Result<Completion, LlmError>;
+}
+
+/// OpenAI's API or any server speaking it, like Ollama or vLLM.
+pub struct OpenAi {
+    model: String,
+}
+
+impl OpenAi {
+    /// The `openai` crate keeps the key and base URL in globals, so they are set once here
+    /// rather than per request, and one process talks to one server.
+    pub fn new(key: String, base_url: Option<String>, model: String) -> Self {
+        set_key(key);
+        if let Some(base_url) = base_url {
+            set_base_url(base_url);
+        }
+        Self { model }
+    }
+}
+
+/// OpenAI answers 429 with this code when our key is over its quota.
+fn is_rate_limit(err: &OpenAiError) -> bool {
+    err.code.as_deref() == Some("rate_limit_exceeded")
+}
+
+#[async_trait]
+impl LlmProvider for OpenAi {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn complete(&self, prompt: &str, text: &str, max_tokens: u64) -> Result<Completion, LlmError> {
+        let messages = [
+            ChatCompletionMessage {
+                role: ChatCompletionMessageRole::System,
+                content: Some(prompt.to_string()),
+                name: None,
+                function_call: None,
+            },
+            ChatCompletionMessage {
+                role: ChatCompletionMessageRole::User,
+                content: Some(text.to_string()),
+                name: None,
+                function_call: None,
+            },
+        ];
+        let completion = ChatCompletion::builder(&self.model, messages)
+            .temperature(0.7)
+            .max_tokens(max_tokens)
+            .create()
+            .await
+            .map_err(|err| if is_rate_limit(&err) { LlmError::RateLimited(err.to_string()) } else { LlmError::Unavailable(err.to_string()) })?;
+        let total_tokens = completion.usage.as_ref().map(|usage| usage.total_tokens as usize);
+        let content = completion
+            .choices
+            .into_iter()
+            .filter(|choice| choice.message.role == ChatCompletionMessageRole::Assistant)
+            .find_map(|choice| choice.message.content)
+            .ok_or_else(|| LlmError::Unavailable("no answer in the completion".to_string()))?;
+        Ok(Completion { content, total_tokens })
+    }
+}
+
+/// Answers without any network, for running the bot offline: the books it was given whose title
+/// appears in the text, so the same transcript always gives the same list.
+pub struct Mock {
+    books: Vec<Book>,
+}
+
+#[async_trait]
+impl LlmProvider for Mock {
+    fn model(&self) -> &str {
+        "mock"
+    }
+
+    async fn complete(&self, _prompt: &str, text: &str, _max_tokens: u64) -> Result<Completion, LlmError> {
+        let text = text.to_lowercase();
+        let found: Vec<&Book> = self.books.iter().filter(|book| text.contains(&book.title.to_lowercase())).collect();
+        let content = serde_json::to_string(&found).map_err(|err| LlmError::Unavailable(err.to_string()))?;
+        Ok(Completion { content, total_tokens: None })
+    }
+}
+
+fn required(name: &str) -> Result<String, String> {
+    match env::var(name) {
+        Ok(value) if value.is_empty() => Err(format!("{} env is set but empty", name)),
+        Ok(value) => Ok(value),
+        Err(env::VarError::NotPresent) => Err(format!("{} env is not set", name)),
+        Err(err) => Err(format!("Failed to read {} env: {}", name, err)),
+    }
+}
+
+/// The provider named by `LLM_PROVIDER`, `openai` by default.
+pub fn from_env() -> Result<Arc<dyn LlmProvider>, String> {
+    let provider = env::var("LLM_PROVIDER").unwrap_or_else(|_| "openai".to_string());
+    match provider.as_str() {
+        "openai" => {
+            let model = env::var("LLM_MODEL").unwrap_or_else(|_| "gpt-4o-mini".to_string());
+            Ok(Arc::new(OpenAi::new(required("OPENAI_TOKEN")?, None, model)))
+        }
+        "openai-compatible" => {
+            let base_url = required("LLM_BASE_URL")?;
+            // Local servers usually accept any key
+            let key = env::var("LLM_API_KEY").unwrap_or_default();
+            Ok(Arc::new(OpenAi::new(key, Some(base_url), required("LLM_MODEL")?)))
+        }
+        "mock" => {
+            let books = env::var("LLM_MOCK_BOOKS").unwrap_or_else(|_| "[]".to_string());
+            let books = serde_json::from_str(&books).map_err(|err| format!("Invalid LLM_MOCK_BOOKS: {}", err))?;
+            Ok(Arc::new(Mock { books }))
+        }
+        other => Err(format!("Unknown LLM_PROVIDER {}, expected openai, openai-compatible or mock", other)),
+    }
+}
@@ -0,0 +1,299 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::future::{BoxFuture, Shared};
+use tokio::sync::{watch, Semaphore};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::cache;
+use crate::chunks::Chunker;
+use crate::error::ExtractError;
+use crate::extract_json::{self, Book};
+use crate::llm::LlmProvider;
+use crate::progress::{Progress, Stage};
+use crate::rate_limiter;
+use crate::singleflight::SingleFlight;
+use crate::subtitles::{self, Cue};
+use crate::tracks::{Track, TrackKind, TrackPolicy};
+use crate::ytdlp::{self, VideoInfo, YtDlp};
+
+/// Outcome of a finished extraction, shared by everyone who asked for the same video.
+#[derive(Clone, Debug)]
+pub struct Extraction {
+    pub info: VideoInfo,
+    pub lang: String,
+    pub kind: TrackKind,
+    pub books: Vec<Book>,
+}
+
+type ExtractionResult = Result<Extraction, ExtractError>;
+
+/// State of a running job shared by everyone waiting for it.
+/// Nobody joins once `waiters` drops to zero, so a job is never cancelled under a new caller.
+struct FlightState {
+    progress: Progress,
+    cancel: CancellationToken,
+    waiters: Arc<AtomicUsize>,
+}
+
+impl Clone for FlightState {
+    fn clone(&self) -> Self {
+        FlightState {
+            progress: self.progress.clone(),
+            cancel: self.cancel.clone(),
+            waiters: Arc::clone(&self.waiters),
+        }
+    }
+}
+
+/// One caller's view of a running extraction job.
+pub struct Flight {
+    pub stage: watch::Receiver<Stage>,
+    pub result: Shared<BoxFuture<'static, ExtractionResult>>,
+    state: FlightState,
+}
+
+impl FlightState {
+    /// Counts one more waiter unless everyone has given the job up already.
+    fn join(&self) -> bool {
+        self.waiters.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |waiters| (waiters > 0).then_some(waiters + 1)).is_ok()
+    }
+}
+
+impl Flight {
+    /// Stops waiting for the job; the job itself is cancelled once nobody waits for it.
+    pub fn abandon(self) {
+        if self.state.waiters.fetch_sub(1, Ordering::SeqCst) == 1 {
+            log::info!("Nobody waits for the job anymore, cancelling it");
+            self.state.cancel.cancel();
+        }
+    }
+}
+
+/// Everything a request needs to turn a YouTube link into a list of books.
+pub struct Pipeline {
+    pub rate_limiter: rate_limiter::RateLimiterWrapper,
+    pub cache: cache::ResultCache,
+    ytdlp: YtDlp,
+    tracks: Arc<TrackPolicy>,
+    chunker: Chunker,
+    llm: Arc<dyn LlmProvider>,
+    llm_slots: Arc<Semaphore>,
+    in_flight: SingleFlight<ExtractionResult, FlightState>,
+    /// Metadata lookups by video ID, so everyone sending a video at once shares one yt-dlp call.
+    lookups: SingleFlight<Result<VideoInfo, ExtractError>, ()>,
+}
+
+impl Clone for Pipeline {
+    fn clone(&self) -> Self {
+        Pipeline {
+            rate_limiter: self.rate_limiter.clone(),
+            cache: self.cache.clone(),
+            ytdlp: self.ytdlp.clone(),
+            tracks: Arc::clone(&self.tracks),
+            chunker: self.chunker.clone(),
+            llm: Arc::clone(&self.llm),
+            llm_slots: Arc::clone(&self.llm_slots),
+            in_flight: self.in_flight.clone(),
+            lookups: self.lookups.clone(),
+        }
+    }
+}
+
+impl Pipeline {
+    /// `llm_concurrency` caps simultaneous LLM calls summed over all running jobs.
+    pub fn new(rate_limiter: rate_limiter::RateLimiterWrapper, cache: cache::ResultCache, ytdlp: YtDlp, tracks: TrackPolicy, chunker: Chunker, llm: Arc<dyn LlmProvider>, llm_concurrency: usize) -> Self {
+        Self {
+            rate_limiter,
+            cache,
+            ytdlp,
+            tracks: Arc::new(tracks),
+            chunker,
+            llm,
+            llm_slots: Arc::new(Semaphore::new(llm_concurrency.max(1))),
+            in_flight: SingleFlight::new(),
+            lookups: SingleFlight::new(),
+        }
+    }
+
+    /// Fetches the video's metadata and refuses videos that cannot be processed.
+    /// Metadata cached with a result was accepted before and is returned as is.
+    pub async fn lookup(&self, url: &str, video_id: &str, cancel: &CancellationToken) -> Result<VideoInfo, ExtractError> {
+        match self.cache.get_info(video_id) {
+            Ok(Some(info)) => {
+                log::info!("Metadata of {} cached: \"{}\"", video_id, info.title);
+                return Ok(info);
+            }
+            Ok(None) => {}
+            Err(err) => log::error!("Failed to read cached metadata of {}: {}", video_id, err),
+        }
+        let ytdlp = self.ytdlp.clone();
+        let owned_url = url.to_string();
+        // Short enough to run to the end for whoever still waits, so a caller leaving does not stop it
+        let job = move |()| async move { ytdlp.info(&owned_url, &CancellationToken::new()).await };
+        let (_, info) = self.lookups.run(video_id, (), job, |panic| Err(ExtractError::Internal(panic)), |_| true);
+        let info = tokio::select! {
+            info = info => info?,
+            _ = cancel.cancelled() => return Err(ExtractError::Cancelled),
+        };
+        log::info!(
+            "Video {}: \"{}\" by {}, {:?}s, {} chapters, {} description chars, live status {}",
+            video_id,
+            info.title,
+            info.channel.as_deref().or(info.uploader.as_deref()).unwrap_or("unknown"),
+            info.duration,
+            info.chapters.len(),
+            info.description.chars().count(),
+            info.live_status.as_deref().unwrap_or("unknown"),
+        );
+        for chapter in &info.chapters {
+            log::debug!("Chapter {:.0}-{:.0}s: {}", chapter.start_time, chapter.end_time, chapter.title);
+        }
+        if info.is_live() {
+            return Err(ExtractError::Live);
+        }
+        let limit = max_video_minutes();
+        if let Some(seconds) = info.duration {
+            let minutes = seconds as u64 / 60;
+            if minutes > limit {
+                return Err(ExtractError::TooLong { minutes, limit });
+            }
+        }
+        Ok(info)
+    }
+
+    /// The track the policy picks; `user_langs` are tried after the video's own language.
+    pub fn choose_track(&self, info: &VideoInfo, user_langs: &[String]) -> Option<Track> {
+        let chain = self.tracks.chain(info.language.as_deref(), user_langs);
+        let track = self.tracks.choose(info, &chain);
+        match &track {
+            Some(track) => log::info!("Chose {:?} subtitles {} from {}", track.kind, track.key, chain.join(", ")),
+            None => log::info!("No subtitles in any of {}", chain.join(", ")),
+        }
+        track
+    }
+
+    /// The books cached for the track of the video, if any.
+    pub fn cached(&self, video_id: &str, track: &Track) -> Option<Vec<Book>> {
+        match self.cache.get(video_id, &track.id(), extract_json::PROMPT_VERSION) {
+            Ok(cached) => cached,
+            Err(err) => {
+                log::error!("Failed to read cache for {}: {}", video_id, err);
+                None
+            }
+        }
+    }
+
+    /// Joins the job running for the video and track, if there is one.
+    pub fn join(&self, video_id: &str, track: &Track) -> Option<Flight> {
+        let (state, result) = self.in_flight.join(&flight_key(video_id, track), FlightState::join)?;
+        log::info!("Joined the running job for {} ({})", video_id, track.id());
+        Some(Flight {
+            stage: state.progress.subscribe(),
+            result,
+            state,
+        })
+    }
+
+    /// Extracts books from the track, joining an already running job for the same video and track.
+    pub fn extract(&self, url: &str, video_id: &str, info: VideoInfo, track: Track) -> Flight {
+        let key = flight_key(video_id, &track);
+        let url = url.to_string();
+        let owned_id = video_id.to_string();
+        let pipeline = self.clone();
+        let job = move |state: FlightState| async move {
+            pipeline.run(&url, &owned_id, info, track, &state).await
+        };
+        let fresh = FlightState {
+            progress: Progress::new(),
+            cancel: CancellationToken::new(),
+            // The caller starting the job
+            waiters: Arc::new(AtomicUsize::new(1)),
+        };
+        let (state, result) = self.in_flight.run(&key, fresh, job, |panic| Err(ExtractError::Internal(panic)), FlightState::join);
+        Flight {
+            stage: state.progress.subscribe(),
+            result,
+            state,
+        }
+    }
+
+    async fn run(&self, url: &str, video_id: &str, info: VideoInfo, track: Track, state: &FlightState) -> Result<Extraction, ExtractError> {
+        let progress = &state.progress;
+        let cancel = &state.cancel;
+        progress.report(Stage::LanguageDetected(track.lang.clone()));
+        if let Some(books) = self.cached(video_id, &track) {
+            log::info!("Cache hit for {} ({})", video_id, track.id());
+            return Ok(Extraction { info, lang: track.lang, kind: track.kind, books });
+        }
+
+        let cues = download_video(&self.ytdlp, url, &track, progress, cancel).await?;
+        let video = extract_json::VideoText { cues: &cues, chapters: &info.chapters, rolling: track.kind == TrackKind::Auto };
+        let extracted = extract_json::extract_json(video, &self.llm, &self.chunker, &self.rate_limiter, &self.llm_slots, progress, cancel).await?;
+        if !extracted.complete {
+            log::info!("Not caching the partial result for {}", video_id);
+        } else if let Err(err) = self.cache.put(video_id, &track.id(), extract_json::PROMPT_VERSION, &extracted.books) {
+            log::error!("Failed to cache result for {}: {}", video_id, err);
+        } else if let Err(err) = self.cache.put_info(video_id, &info) {
+            log::error!("Failed to cache metadata of {}: {}", video_id, err);
+        }
+        Ok(Extraction { info, lang: track.lang, kind: track.kind, books: extracted.books })
+    }
+}
+
+fn flight_key(video_id: &str, track: &Track) -> String {
+    format!("{}:{}", video_id, track.id())
+}
+
+/// Longest video accepted, in minutes; long videos cost many LLM calls.
+fn max_video_minutes() -> u64 {
+    env::var("MAX_VIDEO_MINUTES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(180)
+}
+
+/// Downloads the chosen subtitle track in whatever format YouTube serves and parses it.
+async fn download_video(ytdlp: &YtDlp, url: &str, track: &Track, progress: &Progress, cancel: &CancellationToken) -> Result<Vec<Cue>, ExtractError> {
+    let file_name = Uuid::new_v4();
+    let output = format!("./tmp/{}", file_name);
+    let source = match track.kind {
+        TrackKind::Manual => "--write-subs",
+        TrackKind::Auto | TrackKind::Translated => "--write-auto-subs",
+    };
+    let formats = subtitles::PREFERRED.map(|format| format.extension()).join("/");
+    let args = [
+        source,
+        "--sub-lang", &track.key,
+        "--sub-format", &formats,
+        "--skip-download",
+        "--no-live-from-start",
+        "-o", &output,
+        url,
+    ];
+    ytdlp.run(args, ytdlp::SUBTITLES_TIMEOUT, cancel).await?;
+    // The file is named after the format yt-dlp settled on
+    let written = subtitles::PREFERRED
+        .into_iter()
+        .map(|format| (format, format!("{}.{}.{}", output, track.key, format.extension())))
+        .find(|(_, path)| Path::new(path).exists());
+    // yt-dlp exits fine when the track vanished between the metadata call and now
+    let Some((format, path)) = written else {
+        log::info!("yt-dlp wrote no {} subtitles for {}", track.key, url);
+        return Err(ExtractError::NoSubtitles);
+    };
+    let content = fs::read_to_string(&path);
+    fs::remove_file(&path)?;
+    let cues = subtitles::parse(&content?, format)?;
+    let Some(last) = cues.last() else {
+        log::info!("{} subtitles for {} have no cues", track.key, url);
+        return Err(ExtractError::NoSubtitles);
+    };
+    log::info!("Read {} {:?} cues of {} subtitles up to {:?} for {}", cues.len(), format, track.key, last.end, url);
+    progress.report(Stage::SubtitlesDownloaded);
+    Ok(cues)
+}
@@ -0,0 +1,88 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardMarkup, Message};
+use tokio::sync::watch;
+
+use crate::i18n::Lang;
+
+/// How often the status message may be edited; Telegram throttles frequent edits.
+const EDIT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Stage of an extraction job as shown to the user.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stage {
+    Started,
+    LanguageDetected(String),
+    SubtitlesDownloaded,
+    Analysing { done: usize, total: usize },
+    Merging,
+}
+
+impl Stage {
+    pub fn describe(&self, lang: Lang) -> String {
+        match self {
+            Stage::Started => lang.looking_up().to_string(),
+            Stage::LanguageDetected(detected) => lang.language_detected(detected),
+            Stage::SubtitlesDownloaded => lang.subtitles_downloaded().to_string(),
+            Stage::Analysing { done, total } => lang.analysing(*done, *total),
+            Stage::Merging => lang.merging().to_string(),
+        }
+    }
+}
+
+/// Sending side of a job's progress; cheap to clone into chunk tasks.
+pub struct Progress {
+    tx: Arc<watch::Sender<Stage>>,
+}
+
+impl Clone for Progress {
+    fn clone(&self) -> Self {
+        Progress {
+            tx: Arc::clone(&self.tx),
+        }
+    }
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        let (tx, _) = watch::channel(Stage::Started);
+        Self { tx: Arc::new(tx) }
+    }
+
+    pub fn report(&self, stage: Stage) {
+        log::info!("Progress: {:?}", stage);
+        self.tx.send_replace(stage);
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Stage> {
+        self.tx.subscribe()
+    }
+}
+
+/// Awaits `job` while keeping `status` in sync with the latest reported stage.
+/// `keyboard` is re-attached on every edit, otherwise Telegram drops it.
+pub async fn follow<T>(bot: &Bot, status: &Message, keyboard: &InlineKeyboardMarkup, lang: Lang, progress: watch::Receiver<Stage>, job: impl Future<Output = T>) -> T {
+    tokio::pin!(job);
+    let mut ticker = tokio::time::interval(EDIT_INTERVAL);
+    let mut shown: Option<String> = None;
+    loop {
+        tokio::select! {
+            res = &mut job => return res,
+            _ = ticker.tick() => {
+                let text = progress.borrow().describe(lang);
+                if shown.as_ref() != Some(&text) {
+                    if let Err(err) = bot.edit_message_text(status.chat.id, status.id, text.clone())
+                        .reply_markup(keyboard.clone())
+                        .await
+                    {
+                        log::error!("Failed to update status message: {}", err);
+                    }
+                    shown = Some(text);
+                }
+            }
+        }
+    }
+}
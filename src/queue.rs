@@ -0,0 +1,173 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use futures::future::{BoxFuture, FutureExt};
+use tokio::sync::{watch, Notify};
+
+struct QueuedJob {
+    user_id: u64,
+    position: watch::Sender<usize>,
+    job: BoxFuture<'static, ()>,
+}
+
+struct QueueState {
+    pending: VecDeque<QueuedJob>,
+    // Jobs per user, both waiting and running
+    per_user: HashMap<u64, usize>,
+}
+
+#[derive(Debug)]
+pub struct UserLimitReached;
+
+/// Global FIFO of extraction jobs served by a fixed pool of workers.
+///
+/// Every queued job gets a `watch` channel with its 1-based position in the
+/// queue; the position drops to 0 once a worker picks the job up.
+pub struct JobQueue {
+    state: Arc<Mutex<QueueState>>,
+    notify: Arc<Notify>,
+    max_per_user: usize,
+}
+
+impl Clone for JobQueue {
+    fn clone(&self) -> Self {
+        JobQueue {
+            state: Arc::clone(&self.state),
+            notify: Arc::clone(&self.notify),
+            max_per_user: self.max_per_user,
+        }
+    }
+}
+
+impl JobQueue {
+    /// Creates the queue and spawns `workers` worker tasks on the current runtime.
+    pub fn new(workers: usize, max_per_user: usize) -> Self {
+        let queue = Self {
+            state: Arc::new(Mutex::new(QueueState {
+                pending: VecDeque::new(),
+                per_user: HashMap::new(),
+            })),
+            notify: Arc::new(Notify::new()),
+            max_per_user,
+        };
+        for worker_id in 0..workers.max(1) {
+            let queue_clone = queue.clone();
+            tokio::spawn(async move { queue_clone.work(worker_id).await });
+        }
+        queue
+    }
+
+    /// Puts the job at the back of the queue unless the user already has too many jobs.
+    pub fn push<F>(&self, user_id: u64, job: F) -> Result<watch::Receiver<usize>, UserLimitReached>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut state = self.state.lock().unwrap();
+        let count = state.per_user.entry(user_id).or_insert(0);
+        if *count >= self.max_per_user {
+            return Err(UserLimitReached);
+        }
+        *count += 1;
+        let (position, rx) = watch::channel(state.pending.len() + 1);
+        state.pending.push_back(QueuedJob {
+            user_id,
+            position,
+            job: job.boxed(),
+        });
+        log::info!("Job queued for user {}, queue length {}", user_id, state.pending.len());
+        drop(state);
+        self.notify.notify_one();
+        Ok(rx)
+    }
+
+    fn pop(&self) -> Option<QueuedJob> {
+        let mut state = self.state.lock().unwrap();
+        let next = state.pending.pop_front()?;
+        for (i, queued) in state.pending.iter().enumerate() {
+            let _ = queued.position.send(i + 1);
+        }
+        Some(next)
+    }
+
+    fn finish(&self, user_id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(count) = state.per_user.get_mut(&user_id) {
+            *count -= 1;
+            if *count == 0 {
+                state.per_user.remove(&user_id);
+            }
+        }
+    }
+
+    async fn work(&self, worker_id: usize) {
+        loop {
+            let Some(queued) = self.pop() else {
+                self.notify.notified().await;
+                continue;
+            };
+            log::info!("Worker {} picked up a job for user {}", worker_id, queued.user_id);
+            let _ = queued.position.send(0);
+            // Run in its own task so a panicking job does not take the worker down
+            if let Err(err) = tokio::spawn(queued.job).await {
+                log::error!("Worker {} job panicked: {}", worker_id, err);
+            }
+            self.finish(queued.user_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    /// A job that runs until `release` is sent to.
+    fn held() -> (oneshot::Sender<()>, impl Future<Output = ()> + Send + 'static) {
+        let (release, released) = oneshot::channel();
+        (release, async move {
+            let _ = released.await;
+        })
+    }
+
+    #[tokio::test]
+    async fn positions_move_up_as_workers_pick_jobs() {
+        let queue = JobQueue::new(1, 10);
+        let (release_first, first) = held();
+        let mut first = queue.push(1, first).unwrap();
+        first.wait_for(|position| *position == 0).await.unwrap();
+        let (release_second, second) = held();
+        let mut second = queue.push(2, second).unwrap();
+        let mut third = queue.push(3, async {}).unwrap();
+        assert_eq!((*second.borrow(), *third.borrow()), (1, 2));
+
+        release_first.send(()).unwrap();
+        second.wait_for(|position| *position == 0).await.unwrap();
+        assert_eq!(*third.borrow(), 1);
+        release_second.send(()).unwrap();
+        third.wait_for(|position| *position == 0).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn users_are_held_to_their_limit_until_a_job_finishes() {
+        let queue = JobQueue::new(1, 2);
+        let (release, job) = held();
+        let mut running = queue.push(1, job).unwrap();
+        running.wait_for(|position| *position == 0).await.unwrap();
+        queue.push(1, async {}).unwrap();
+        assert!(queue.push(1, async {}).is_err());
+        // Other users are not affected
+        queue.push(2, async {}).unwrap();
+
+        release.send(()).unwrap();
+        // The user gets a slot back once the held job is done
+        let mut pushed = loop {
+            match queue.push(1, async {}) {
+                Ok(pushed) => break pushed,
+                Err(UserLimitReached) => tokio::task::yield_now().await,
+            }
+        };
+        pushed.wait_for(|position| *position == 0).await.unwrap();
+    }
+}
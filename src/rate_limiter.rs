@@ -147,4 +147,66 @@ impl RateLimiterWrapper {
         log::info!("{}\tMaximum attempts reached. Exiting rate limiting checks.", task_id);
         false // Not allowed after max attempts
     }
+
+    /// Hands back the budget of an allowed request that never went through.
+    pub async fn release(&self, tokens: usize, task_id: &str) {
+        let mut limiter = self.limiter.lock().await;
+        limiter.token_window = limiter.token_window.saturating_sub(tokens);
+        limiter.req_window = limiter.req_window.saturating_sub(1);
+        log::info!("{}\tReleased {} tokens, token window: {}", task_id, tokens, limiter.token_window);
+    }
+
+    /// Hands back tokens an allowed request turned out not to need.
+    pub async fn refund(&self, tokens: usize, task_id: &str) {
+        let mut limiter = self.limiter.lock().await;
+        limiter.token_window = limiter.token_window.saturating_sub(tokens);
+        log::info!("{}\tRefunded {} tokens, token window: {}", task_id, tokens, limiter.token_window);
+    }
+
+    /// Guards the budget taken by an allowed request, see [`Reservation`].
+    pub fn reserve(&self, tokens: usize, task_id: &str) -> Reservation {
+        Reservation {
+            limiter: self.clone(),
+            tokens,
+            task_id: task_id.to_string(),
+            committed: false,
+        }
+    }
+}
+
+/// Budget of an allowed request; released back to the limiter if dropped before [`Reservation::commit`],
+/// e.g. when the task making the request gets aborted.
+pub struct Reservation {
+    limiter: RateLimiterWrapper,
+    tokens: usize,
+    task_id: String,
+    committed: bool,
+}
+
+impl Reservation {
+    pub fn commit(mut self) {
+        self.committed = true;
+    }
+
+    /// Commits only the tokens the request actually used, as reported by the provider.
+    pub async fn settle(mut self, used: usize) {
+        self.committed = true;
+        if used < self.tokens {
+            self.limiter.refund(self.tokens - used, &self.task_id).await;
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        let limiter = self.limiter.clone();
+        let tokens = self.tokens;
+        let task_id = std::mem::take(&mut self.task_id);
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move { limiter.release(tokens, &task_id).await });
+        }
+    }
 }
@@ -0,0 +1,208 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+
+use crate::i18n::Lang;
+
+const CALLBACK_PREFIX: &str = "settings:";
+
+/// Fallback subtitle languages offered in the settings menu, `None` means none.
+const LANG_CHOICES: [Option<&str>; 6] = [None, Some("en"), Some("ru"), Some("de"), Some("es"), Some("fr")];
+
+/// Reply languages offered in the settings menu, `None` follows the Telegram client.
+const REPLY_LANG_CHOICES: [Option<&str>; 3] = [None, Some("en"), Some("ru")];
+
+/// How the list of found books is rendered.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Plain,
+    Numbered,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "plain" => Ok(OutputFormat::Plain),
+            "numbered" => Ok(OutputFormat::Numbered),
+            other => Err(format!("Unknown format: {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputFormat::Plain => write!(f, "plain"),
+            OutputFormat::Numbered => write!(f, "numbered"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct UserSettings {
+    /// Subtitle language to fall back to when the video's own one is unknown or has no subtitles.
+    pub lang: Option<String>,
+    pub format: OutputFormat,
+    /// Show when each book is first mentioned.
+    pub timestamps: bool,
+    /// Language of the bot's replies, taken from the Telegram client when unset.
+    pub reply_lang: Option<String>,
+}
+
+/// A settings menu button press.
+pub enum MenuAction {
+    Lang,
+    Format,
+    Timestamps,
+    ReplyLang,
+}
+
+/// Per-user preferences persisted in SQLite, keyed by Telegram user ID.
+pub struct SettingsStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Clone for SettingsStore {
+    fn clone(&self) -> Self {
+        SettingsStore {
+            conn: Arc::clone(&self.conn),
+        }
+    }
+}
+
+impl SettingsStore {
+    pub fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS user_settings (
+                user_id INTEGER PRIMARY KEY,
+                lang TEXT,
+                format TEXT NOT NULL,
+                timestamps INTEGER NOT NULL,
+                reply_lang TEXT
+            );",
+        )?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    fn load(&self, user_id: u64) -> Result<UserSettings, Box<dyn Error>> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let row = conn
+            .query_row(
+                "SELECT lang, format, timestamps, reply_lang FROM user_settings WHERE user_id = ?1",
+                params![user_id as i64],
+                |row| {
+                    Ok((
+                        row.get::<_, Option<String>>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, bool>(2)?,
+                        row.get::<_, Option<String>>(3)?,
+                    ))
+                },
+            )
+            .optional()?;
+        Ok(match row {
+            Some((lang, format, timestamps, reply_lang)) => UserSettings {
+                lang,
+                format: format.parse().unwrap_or_default(),
+                timestamps,
+                reply_lang,
+            },
+            None => UserSettings::default(),
+        })
+    }
+
+    /// Settings of the user, defaults if there are none or the store is unavailable.
+    pub fn get(&self, user_id: u64) -> UserSettings {
+        self.load(user_id).unwrap_or_else(|err| {
+            log::error!("Failed to load settings of {}: {}", user_id, err);
+            UserSettings::default()
+        })
+    }
+
+    pub fn update(&self, user_id: u64, change: impl FnOnce(&mut UserSettings)) -> Result<UserSettings, Box<dyn Error>> {
+        let mut settings = self.load(user_id)?;
+        change(&mut settings);
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT OR REPLACE INTO user_settings (user_id, lang, format, timestamps, reply_lang)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                user_id as i64,
+                settings.lang,
+                settings.format.to_string(),
+                settings.timestamps,
+                settings.reply_lang,
+            ],
+        )?;
+        Ok(settings)
+    }
+}
+
+/// Picks the value following `current` in `choices`, wrapping around.
+fn next_choice(choices: &[Option<&str>], current: &Option<String>) -> Option<String> {
+    let pos = choices
+        .iter()
+        .position(|choice| *choice == current.as_deref())
+        .unwrap_or(0);
+    choices[(pos + 1) % choices.len()].map(str::to_string)
+}
+
+impl MenuAction {
+    /// Applies the button press, every button cycles through its possible values.
+    pub fn apply(&self, settings: &mut UserSettings) {
+        match self {
+            MenuAction::Lang => settings.lang = next_choice(&LANG_CHOICES, &settings.lang),
+            MenuAction::Format => {
+                settings.format = match settings.format {
+                    OutputFormat::Plain => OutputFormat::Numbered,
+                    OutputFormat::Numbered => OutputFormat::Plain,
+                }
+            }
+            MenuAction::Timestamps => settings.timestamps = !settings.timestamps,
+            MenuAction::ReplyLang => settings.reply_lang = next_choice(&REPLY_LANG_CHOICES, &settings.reply_lang),
+        }
+    }
+}
+
+pub fn describe(settings: &UserSettings, lang: Lang) -> String {
+    [
+        lang.fallback_lang(settings.lang.as_deref()),
+        lang.output_format(&settings.format.to_string()),
+        lang.timestamps(settings.timestamps),
+        lang.reply_lang(settings.reply_lang.as_deref()),
+    ]
+    .join("\n")
+}
+
+pub fn keyboard(settings: &UserSettings, lang: Lang) -> InlineKeyboardMarkup {
+    let button = |text: String, action: &str| {
+        [InlineKeyboardButton::callback(text, format!("{}{}", CALLBACK_PREFIX, action))]
+    };
+    InlineKeyboardMarkup::new([
+        button(lang.fallback_lang(settings.lang.as_deref()), "lang"),
+        button(lang.output_format(&settings.format.to_string()), "format"),
+        button(lang.timestamps(settings.timestamps), "timestamps"),
+        button(lang.reply_lang(settings.reply_lang.as_deref()), "reply_lang"),
+    ])
+}
+
+/// Extracts the menu action from the data of a settings button press.
+pub fn parse_callback(data: &str) -> Option<MenuAction> {
+    match data.strip_prefix(CALLBACK_PREFIX)? {
+        "lang" => Some(MenuAction::Lang),
+        "format" => Some(MenuAction::Format),
+        "timestamps" => Some(MenuAction::Timestamps),
+        "reply_lang" => Some(MenuAction::ReplyLang),
+        _ => None,
+    }
+}
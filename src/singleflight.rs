@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::future::{BoxFuture, FutureExt, Shared};
+
+type Flight<T, C> = (C, Shared<BoxFuture<'static, T>>);
+/// Each job under its number, so a job replaced while finishing does not remove its successor.
+type Jobs<T, C> = Mutex<HashMap<String, (u64, Flight<T, C>)>>;
+
+/// Collapses concurrent calls for the same key into a single running job.
+///
+/// The first caller spawns the job, everyone arriving while it is still running
+/// awaits the same result. The job is detached from its callers, so it finishes
+/// (and gets cached) even if the caller that started it goes away.
+///
+/// `C` is a handle handed to the job and shared with every joiner, e.g. a progress channel.
+pub struct SingleFlight<T: Clone, C: Clone> {
+    in_flight: Arc<Jobs<T, C>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl<T: Clone, C: Clone> Clone for SingleFlight<T, C> {
+    fn clone(&self) -> Self {
+        SingleFlight {
+            in_flight: Arc::clone(&self.in_flight),
+            next_id: Arc::clone(&self.next_id),
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static, C: Clone + Send + 'static> SingleFlight<T, C> {
+    pub fn new() -> Self {
+        Self {
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Joins the job running under `key`, or starts `job(ctx)` if there is none.
+    /// Returns the handle of the running job together with its shared result.
+    /// `on_error` turns a panicked job into a value every waiter receives.
+    /// `join` is called on the running job's handle while the map is locked and registers the caller
+    /// with it; a job it refuses, say one given up by everyone, is replaced as it only has its cleanup left.
+    /// `ctx` of a new job is expected to count its caller already.
+    pub fn run<F>(&self, key: &str, ctx: C, job: impl FnOnce(C) -> F, on_error: fn(String) -> T, join: fn(&C) -> bool) -> Flight<T, C>
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some((_, (ctx, shared))) = in_flight.get(key) {
+            if join(ctx) {
+                log::info!("Joining in-flight job for {}", key);
+                return (ctx.clone(), shared.clone());
+            }
+            log::info!("Replacing the cancelled job for {}", key);
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let map = Arc::clone(&self.in_flight);
+        let owned_key = key.to_string();
+        let job = job(ctx.clone());
+        let handle = tokio::spawn(async move {
+            let res = job.await;
+            remove(&map, &owned_key, id);
+            res
+        });
+        let map = Arc::clone(&self.in_flight);
+        let owned_key = key.to_string();
+        let shared = async move {
+            match handle.await {
+                Ok(res) => res,
+                Err(err) => {
+                    // The job never reached its own cleanup
+                    remove(&map, &owned_key, id);
+                    on_error(err.to_string())
+                }
+            }
+        }
+        .boxed()
+        .shared();
+        in_flight.insert(key.to_string(), (id, (ctx.clone(), shared.clone())));
+        (ctx, shared)
+    }
+
+    /// Joins the job running under `key` like `run` does, without starting one when there is none.
+    pub fn join(&self, key: &str, join: fn(&C) -> bool) -> Option<Flight<T, C>> {
+        let in_flight = self.in_flight.lock().unwrap();
+        let (_, (ctx, shared)) = in_flight.get(key)?;
+        join(ctx).then(|| (ctx.clone(), shared.clone()))
+    }
+}
+
+/// Removes the job numbered `id` from the map, unless another one has taken its key since.
+fn remove<T: Clone, C: Clone>(map: &Jobs<T, C>, key: &str, id: u64) {
+    let mut map = map.lock().unwrap();
+    if map.get(key).is_some_and(|(running, _)| *running == id) {
+        map.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn cancelled_jobs_are_not_joined() {
+        let flights: SingleFlight<&str, CancellationToken> = SingleFlight::new();
+        let job = |name| move |cancel: CancellationToken| async move {
+            cancel.cancelled().await;
+            name
+        };
+        let alive = |cancel: &CancellationToken| !cancel.is_cancelled();
+        let (first, first_result) = flights.run("video", CancellationToken::new(), job("first"), |_| "panicked", alive);
+        first.cancel();
+        let (second, second_result) = flights.run("video", CancellationToken::new(), job("second"), |_| "panicked", alive);
+        assert!(!second.is_cancelled());
+        assert_eq!(first_result.await, "first");
+        // The first job finishing leaves the second one in place to be joined
+        let (joined, _) = flights.run("video", CancellationToken::new(), job("third"), |_| "panicked", alive);
+        joined.cancel();
+        assert!(second.is_cancelled());
+        assert_eq!(second_result.await, "second");
+    }
+}
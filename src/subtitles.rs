@@ -0,0 +1,245 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::error::ExtractError;
+
+/// One caption shown on screen; `text` keeps the caption's lines separated by `\n`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cue {
+    pub start: Duration,
+    pub end: Duration,
+    pub text: String,
+}
+
+/// Subtitle formats the bot reads without converting them first.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// YouTube's own JSON, what yt-dlp gets for every YouTube track.
+    Json3,
+    Vtt,
+    Srt,
+}
+
+/// Shorter runs repeated across cues are more likely speech, like "thank you" / "thank you very much".
+const MIN_OVERLAP: usize = 3;
+
+/// Formats to ask yt-dlp for, best first.
+pub const PREFERRED: [Format; 3] = [Format::Json3, Format::Vtt, Format::Srt];
+
+impl Format {
+    /// File extension yt-dlp gives the format, also its name for `--sub-format`.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Json3 => "json3",
+            Format::Vtt => "vtt",
+            Format::Srt => "srt",
+        }
+    }
+}
+
+pub fn parse(content: &str, format: Format) -> Result<Vec<Cue>, ExtractError> {
+    match format {
+        Format::Json3 => parse_json3(content),
+        // WebVTT is SRT with a header, optional cue settings and inline tags, one parser reads both
+        Format::Vtt | Format::Srt => Ok(parse_blocks(content)),
+    }
+}
+
+/// `01:02:03,456`, `01:02:03.456` or `02:03.456`.
+fn timestamp(text: &str) -> Option<Duration> {
+    let (clock, millis) = text.trim().split_once(['.', ','])?;
+    let mut seconds = 0;
+    for part in clock.split(':') {
+        seconds = seconds * 60 + part.parse::<u64>().ok()?;
+    }
+    Some(Duration::from_secs(seconds) + Duration::from_millis(millis.parse().ok()?))
+}
+
+/// `00:00:01.000 --> 00:00:04.000 align:start position:0%`; the cue settings are ignored.
+fn timing(line: &str) -> Option<(Duration, Duration)> {
+    let (start, rest) = line.split_once("-->")?;
+    let end = rest.split_whitespace().next()?;
+    Some((timestamp(start)?, timestamp(end)?))
+}
+
+/// Drops markup such as `<i>` or VTT's per-word `<00:00:01.079><c>` and decodes the escapes WebVTT requires.
+fn clean(line: &str) -> String {
+    let mut text = String::with_capacity(line.len());
+    let mut in_tag = false;
+    for ch in line.chars() {
+        match ch {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => text.push(ch),
+            _ => {}
+        }
+    }
+    let text = text
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&");
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Reads cues as blocks that start with a timing line and end at an empty line.
+/// Anything outside a block, like SRT's cue numbers or VTT's header and notes, is skipped,
+/// while numbers inside a cue are kept as text.
+fn parse_blocks(content: &str) -> Vec<Cue> {
+    let mut cues = vec![];
+    let mut current: Option<Cue> = None;
+    for raw in content.lines() {
+        let line = raw.trim();
+        if let Some((start, end)) = timing(line) {
+            cues.extend(current.take());
+            current = Some(Cue { start, end, text: String::new() });
+        } else if raw.is_empty() {
+            // YouTube pads rolling cues with lines of a single space, those do not end the cue
+            cues.extend(current.take());
+        } else if let Some(cue) = current.as_mut() {
+            let text = clean(line);
+            if !text.is_empty() {
+                if !cue.text.is_empty() {
+                    cue.text.push('\n');
+                }
+                cue.text.push_str(&text);
+            }
+        }
+    }
+    cues.extend(current);
+    cues.retain(|cue| !cue.text.is_empty());
+    cues
+}
+
+#[derive(Deserialize)]
+struct Json3 {
+    #[serde(default)]
+    events: Vec<Json3Event>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Json3Event {
+    #[serde(default)]
+    t_start_ms: u64,
+    #[serde(default)]
+    d_duration_ms: u64,
+    /// Missing on events that only position the window.
+    #[serde(default)]
+    segs: Vec<Json3Segment>,
+}
+
+#[derive(Deserialize)]
+struct Json3Segment {
+    #[serde(default)]
+    utf8: String,
+}
+
+/// Auto-generated tracks split cues into per-word segments and add events holding only a line break.
+fn parse_json3(content: &str) -> Result<Vec<Cue>, ExtractError> {
+    let json: Json3 = serde_json::from_str(content)
+        .map_err(|err| ExtractError::Internal(format!("Unreadable json3 subtitles: {}", err)))?;
+    let cues = json.events
+        .into_iter()
+        .filter_map(|event| {
+            let text: String = event.segs.iter().map(|seg| seg.utf8.as_str()).collect();
+            let text = text
+                .lines()
+                .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+                .join("\n");
+            (!text.is_empty()).then(|| Cue {
+                start: Duration::from_millis(event.t_start_ms),
+                end: Duration::from_millis(event.t_start_ms + event.d_duration_ms),
+                text,
+            })
+        })
+        .collect();
+    Ok(cues)
+}
+
+/// Rolling auto-captions show every line two or three times: typed in word by word, then
+/// moved up while the next line appears. Keeps only the words each cue adds to what was already said,
+/// timed by the cue that first showed them.
+pub fn merge_rolling(cues: &[Cue]) -> Vec<Cue> {
+    let mut said: Vec<String> = vec![];
+    let mut merged = vec![];
+    for cue in cues {
+        let words: Vec<&str> = cue.text.split_whitespace().collect();
+        let lower: Vec<String> = words.iter().map(|word| word.to_lowercase()).collect();
+        let overlap = overlap(&said, &lower);
+        if overlap == words.len() {
+            continue;
+        }
+        said.extend_from_slice(&lower[overlap..]);
+        merged.push(Cue {
+            start: cue.start,
+            end: cue.end,
+            text: words[overlap..].join(" "),
+        });
+    }
+    merged
+}
+
+/// Length of the longest run of words that ends `said` and starts `words`.
+fn overlap(said: &[String], words: &[String]) -> usize {
+    (1..=words.len().min(said.len()))
+        .rev()
+        .find(|&len| said[said.len() - len..] == words[..len])
+        .filter(|&len| len >= MIN_OVERLAP || len == words.len())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transcript(cues: &[Cue]) -> String {
+        cues.iter().map(|cue| cue.text.as_str()).collect::<Vec<_>>().join(" ")
+    }
+
+    #[test]
+    fn merges_english_auto_captions() {
+        let cues = parse(include_str!("../tests/fixtures/rolling_en.vtt"), Format::Vtt).unwrap();
+        assert_eq!(cues.len(), 10);
+        let merged = merge_rolling(&cues);
+        assert_eq!(
+            transcript(&merged),
+            "so today I want to talk about a book that changed my life it's called thinking fast and slow \
+             by daniel kahneman and i read it in 2011 and it was so so good"
+        );
+        assert_eq!(merged.len(), 5);
+        assert_eq!(merged[3].start, Duration::from_millis(8760));
+    }
+
+    #[test]
+    fn merges_russian_auto_captions() {
+        let cues = parse(include_str!("../tests/fixtures/rolling_ru.vtt"), Format::Vtt).unwrap();
+        let merged = merge_rolling(&cues);
+        assert_eq!(
+            transcript(&merged),
+            "всем привет сегодня расскажу про роман мастер и маргарита булгакова я перечитывал его раза три \
+             а ещё собачье сердце"
+        );
+    }
+
+    #[test]
+    fn reads_json3_auto_captions() {
+        let cues = parse(include_str!("../tests/fixtures/auto_en.json3"), Format::Json3).unwrap();
+        // Window events without segments and line breaks alone give no cues
+        let texts: Vec<&str> = cues.iter().map(|cue| cue.text.as_str()).collect();
+        assert_eq!(texts, vec!["so today I want to talk", "about thinking fast and slow", "by Daniel Kahneman"]);
+        assert_eq!(cues[1].start, Duration::from_millis(2560));
+        assert_eq!(cues[1].end, Duration::from_millis(7760));
+    }
+
+    #[test]
+    fn keeps_short_repeats_and_numbers() {
+        let srt = "1\n00:00:01,000 --> 00:00:02,000\nthank you\n\n2\n00:00:02,000 --> 00:00:04,000\nthank you very much\n1984\n";
+        let cues = parse(srt, Format::Srt).unwrap();
+        assert_eq!(cues[1].text, "thank you very much\n1984");
+        assert_eq!(transcript(&merge_rolling(&cues)), "thank you thank you very much 1984");
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use teloxide::types::{ChatId, InlineKeyboardButton, InlineKeyboardMarkup};
+
+use crate::cancel;
+use crate::i18n::Lang;
+use crate::ytdlp::VideoInfo;
+
+const CALLBACK_PREFIX: &str = "track:";
+/// Telegram keyboards get unwieldy past this, videos with more tracks are rare.
+const MAX_OPTIONS: usize = 12;
+/// A track not picked by then is given up, with the request waiting for it.
+pub const PICK_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Where a subtitle track comes from, from most to least trustworthy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrackKind {
+    /// Uploaded by the author.
+    Manual,
+    /// Recognised from speech by YouTube.
+    Auto,
+    /// YouTube's machine translation of another track.
+    Translated,
+}
+
+/// The subtitle track chosen for a video.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Track {
+    /// yt-dlp's code for the track, passed to `--sub-lang`.
+    pub key: String,
+    /// Language of the text, e.g. `en` for both `en-US` and `en-orig`.
+    pub lang: String,
+    pub kind: TrackKind,
+}
+
+impl TrackKind {
+    /// Stable name stored with cached results.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TrackKind::Manual => "manual",
+            TrackKind::Auto => "auto",
+            TrackKind::Translated => "translated",
+        }
+    }
+}
+
+impl Track {
+    /// Tells apart tracks of the same language from different sources, as the cache must.
+    pub fn id(&self) -> String {
+        format!("{}:{}", self.key, self.kind.code())
+    }
+
+    fn new(key: &str, kind: TrackKind) -> Self {
+        Track {
+            key: key.to_string(),
+            lang: key.split('-').next().unwrap_or(key).to_string(),
+            kind,
+        }
+    }
+}
+
+/// `en` matches the tracks `en`, `en-US` and `en-orig`. The chain is lowercase while yt-dlp keys
+/// are not, `pt-br` must still match `pt-BR`.
+fn matches(key: &str, lang: &str) -> bool {
+    let key = key.to_lowercase();
+    key == lang || key.strip_prefix(lang).is_some_and(|rest| rest.starts_with('-'))
+}
+
+/// Decides which subtitle track to download.
+/// Human-made subtitles beat auto-generated ones in any language of the chain,
+/// machine translations are used only when the chain has nothing native.
+pub struct TrackPolicy {
+    /// Tried after the video's and the user's languages.
+    defaults: Vec<String>,
+    auto_translate: bool,
+}
+
+impl TrackPolicy {
+    pub fn from_env() -> Self {
+        let defaults = env::var("SUBTITLE_LANGS").unwrap_or_else(|_| "en,ru".to_string());
+        let auto_translate = env::var("SUBTITLE_AUTO_TRANSLATE").unwrap_or_else(|_| "true".to_string());
+        Self {
+            defaults: defaults
+                .split(',')
+                .map(|lang| lang.trim().to_lowercase())
+                .filter(|lang| !lang.is_empty())
+                .collect(),
+            auto_translate: auto_translate.trim() != "false",
+        }
+    }
+
+    /// Languages to look for, in order: the video's own, the user's, then the configured defaults.
+    pub fn chain(&self, video_lang: Option<&str>, user_langs: &[String]) -> Vec<String> {
+        let mut chain: Vec<String> = vec![];
+        let candidates = video_lang
+            .into_iter()
+            .map(str::to_string)
+            .chain(user_langs.iter().cloned())
+            .chain(self.defaults.iter().cloned());
+        for lang in candidates {
+            let lang = lang.trim().to_lowercase();
+            if !lang.is_empty() && !chain.contains(&lang) {
+                chain.push(lang);
+            }
+        }
+        chain
+    }
+
+    pub fn choose(&self, info: &VideoInfo, chain: &[String]) -> Option<Track> {
+        let manual = |lang: &String| {
+            info.subtitles
+                .iter()
+                .find(|key| matches(key, lang))
+                .map(|key| Track::new(key, TrackKind::Manual))
+        };
+        let auto = |translated: bool| {
+            move |lang: &String| {
+                info.automatic_captions
+                    .iter()
+                    .find(|caption| caption.translated == translated && matches(&caption.lang, lang))
+                    .map(|caption| {
+                        let kind = if translated { TrackKind::Translated } else { TrackKind::Auto };
+                        Track::new(&caption.lang, kind)
+                    })
+            }
+        };
+        chain.iter().find_map(manual)
+            .or_else(|| chain.iter().find_map(auto(false)))
+            .or_else(|| if self.auto_translate { chain.iter().find_map(auto(true)) } else { None })
+            // Nothing in the chain, the spoken language is still better than no answer.
+            // Live chat replays are listed among subtitles but are not subtitles
+            .or_else(|| {
+                info.subtitles
+                    .iter()
+                    .find(|key| key.as_str() != "live_chat")
+                    .map(|key| Track::new(key, TrackKind::Manual))
+            })
+            .or_else(|| {
+                info.automatic_captions
+                    .iter()
+                    .find(|caption| !caption.translated)
+                    .map(|caption| Track::new(&caption.lang, TrackKind::Auto))
+            })
+    }
+}
+
+/// Tracks worth offering to the user: `preferred` first, then the author's subtitles and
+/// YouTube's native captions, one per language and source. Machine translations are left out,
+/// YouTube has one for every language.
+pub fn options(info: &VideoInfo, preferred: Option<&Track>) -> Vec<Track> {
+    let manual = info.subtitles
+        .iter()
+        .filter(|key| key.as_str() != "live_chat")
+        .map(|key| Track::new(key, TrackKind::Manual));
+    // yt-dlp lists the spoken language twice, as `en` and `en-orig`; the plain key goes first so it is the one kept
+    let mut auto: Vec<Track> = info.automatic_captions
+        .iter()
+        .filter(|caption| !caption.translated)
+        .map(|caption| Track::new(&caption.lang, TrackKind::Auto))
+        .collect();
+    auto.sort_by_key(|track| track.key.ends_with("-orig"));
+    let mut options: Vec<Track> = preferred.cloned().into_iter().collect();
+    for track in manual.chain(auto) {
+        if !options.iter().any(|option| option.lang == track.lang && option.kind == track.kind) {
+            options.push(track);
+        }
+    }
+    options.truncate(MAX_OPTIONS);
+    options
+}
+
+struct Pick<T> {
+    chat_id: ChatId,
+    user_id: u64,
+    video: T,
+}
+
+/// Videos waiting for their user to pick a subtitle track, keyed by request ID.
+/// Each user has at most one pending pick per chat, so people in a group do not replace each other's.
+pub struct PendingPicks<T> {
+    picks: Arc<Mutex<HashMap<String, Pick<T>>>>,
+}
+
+impl<T> Clone for PendingPicks<T> {
+    fn clone(&self) -> Self {
+        PendingPicks {
+            picks: Arc::clone(&self.picks),
+        }
+    }
+}
+
+impl<T> PendingPicks<T> {
+    pub fn new() -> Self {
+        Self {
+            picks: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Stores the video and returns the one it replaces, the user's earlier pick in the same chat.
+    pub fn insert(&self, request_id: &str, chat_id: ChatId, user_id: u64, video: T) -> Option<T> {
+        let mut picks = self.picks.lock().unwrap();
+        let previous = picks
+            .iter()
+            .find(|(_, pick)| pick.chat_id == chat_id && pick.user_id == user_id)
+            .map(|(id, _)| id.clone());
+        let previous = previous.and_then(|id| picks.remove(&id)).map(|pick| pick.video);
+        picks.insert(request_id.to_string(), Pick { chat_id, user_id, video });
+        previous
+    }
+
+    /// Takes the video out if it is still waiting and the pick belongs to the user.
+    pub fn take(&self, request_id: &str, user_id: u64) -> Option<T> {
+        let mut picks = self.picks.lock().unwrap();
+        match picks.get(request_id) {
+            Some(pick) if pick.user_id == user_id => picks.remove(request_id).map(|pick| pick.video),
+            _ => None,
+        }
+    }
+
+    /// Takes the video out whoever it belongs to, if it is still waiting.
+    pub fn remove(&self, request_id: &str) -> Option<T> {
+        self.picks.lock().unwrap().remove(request_id).map(|pick| pick.video)
+    }
+}
+
+/// One button per track, two in a row, with the request's Cancel button below.
+pub fn keyboard(request_id: &str, options: &[Track], lang: Lang) -> InlineKeyboardMarkup {
+    let buttons: Vec<InlineKeyboardButton> = options
+        .iter()
+        .enumerate()
+        .map(|(index, track)| {
+            InlineKeyboardButton::callback(
+                lang.track_label(&track.key, track.kind),
+                format!("{}{}:{}", CALLBACK_PREFIX, request_id, index),
+            )
+        })
+        .collect();
+    let rows = buttons.chunks(2).map(<[InlineKeyboardButton]>::to_vec);
+    cancel::keyboard(request_id, lang)
+        .inline_keyboard
+        .into_iter()
+        .fold(InlineKeyboardMarkup::new(rows), InlineKeyboardMarkup::append_row)
+}
+
+/// Extracts the request ID and the index of the chosen track from the data of a track button press.
+pub fn parse_callback(data: &str) -> Option<(&str, usize)> {
+    let (request_id, index) = data.strip_prefix(CALLBACK_PREFIX)?.rsplit_once(':')?;
+    Some((request_id, index.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ytdlp::Caption;
+
+    fn caption(lang: &str, translated: bool) -> Caption {
+        Caption { lang: lang.to_string(), translated }
+    }
+
+    fn policy(defaults: &[&str], auto_translate: bool) -> TrackPolicy {
+        TrackPolicy { defaults: defaults.iter().map(|lang| lang.to_string()).collect(), auto_translate }
+    }
+
+    fn langs(langs: &[&str]) -> Vec<String> {
+        langs.iter().map(|lang| lang.to_string()).collect()
+    }
+
+    #[test]
+    fn chain_puts_video_then_user_then_defaults() {
+        let chain = policy(&["en", "ru"], true).chain(Some("pt-BR"), &langs(&["RU", " de "]));
+        assert_eq!(chain, langs(&["pt-br", "ru", "de", "en"]));
+    }
+
+    #[test]
+    fn choose_prefers_manual_in_any_language_of_the_chain() {
+        let info = VideoInfo {
+            subtitles: langs(&["ru"]),
+            automatic_captions: vec![caption("en", false), caption("ru", true)],
+            ..VideoInfo::default()
+        };
+        let track = policy(&[], true).choose(&info, &langs(&["en", "ru"]));
+        assert_eq!(track, Some(Track::new("ru", TrackKind::Manual)));
+    }
+
+    #[test]
+    fn choose_matches_regional_keys_whatever_the_case() {
+        let info = VideoInfo {
+            subtitles: langs(&["pt-BR", "zh-Hans"]),
+            automatic_captions: vec![caption("en", true)],
+            ..VideoInfo::default()
+        };
+        let chain = policy(&["en"], true).chain(Some("pt-BR"), &[]);
+        assert_eq!(policy(&["en"], true).choose(&info, &chain), Some(Track::new("pt-BR", TrackKind::Manual)));
+        assert_eq!(policy(&[], true).choose(&info, &langs(&["zh-hans"])), Some(Track::new("zh-Hans", TrackKind::Manual)));
+    }
+
+    #[test]
+    fn choose_translates_only_when_allowed() {
+        let info = VideoInfo {
+            subtitles: langs(&["live_chat"]),
+            automatic_captions: vec![caption("de", false), caption("en", true)],
+            ..VideoInfo::default()
+        };
+        let chain = langs(&["en"]);
+        assert_eq!(policy(&[], true).choose(&info, &chain), Some(Track::new("en", TrackKind::Translated)));
+        // Falls back to the spoken language, never to the live chat replay
+        assert_eq!(policy(&[], false).choose(&info, &chain), Some(Track::new("de", TrackKind::Auto)));
+    }
+
+    #[test]
+    fn options_drop_orig_twins_and_translations() {
+        let info = VideoInfo {
+            automatic_captions: vec![caption("de", true), caption("en", false), caption("en-orig", false)],
+            ..VideoInfo::default()
+        };
+        let preferred = TrackPolicy { defaults: vec![], auto_translate: true }.choose(&info, &["en".to_string()]);
+        let options = options(&info, preferred.as_ref());
+        assert_eq!(options, vec![Track::new("en", TrackKind::Auto)]);
+    }
+
+    #[test]
+    fn options_offer_each_language_and_source() {
+        let info = VideoInfo {
+            subtitles: vec!["en".to_string(), "live_chat".to_string()],
+            automatic_captions: vec![caption("en-orig", false), caption("en", false), caption("ru", true)],
+            ..VideoInfo::default()
+        };
+        let keys: Vec<(String, TrackKind)> = options(&info, None).into_iter().map(|track| (track.key, track.kind)).collect();
+        assert_eq!(keys, vec![("en".to_string(), TrackKind::Manual), ("en".to_string(), TrackKind::Auto)]);
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::{Output, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::de::{Deserializer, IgnoredAny};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::ExtractError;
+
+/// Metadata lookups are quick unless YouTube is throttling us.
+pub const METADATA_TIMEOUT: Duration = Duration::from_secs(60);
+/// Subtitle downloads may try several formats before converting.
+pub const SUBTITLES_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Chapter {
+    pub start_time: f64,
+    pub end_time: f64,
+    #[serde(default)]
+    pub title: String,
+}
+
+/// A caption track generated by YouTube.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Caption {
+    /// yt-dlp's code for the track, e.g. `en` or `en-orig`.
+    pub lang: String,
+    /// Machine-translated from the spoken language rather than recognised from speech.
+    pub translated: bool,
+}
+
+#[derive(Deserialize)]
+struct TrackFormat {
+    #[serde(default)]
+    url: String,
+}
+
+/// What yt-dlp knows about a video, taken from a single `--dump-single-json` call.
+/// Cached with the video's results as it serializes, which reads back in too.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct VideoInfo {
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub title: String,
+    pub channel: Option<String>,
+    pub uploader: Option<String>,
+    /// Seconds, unknown for live streams.
+    pub duration: Option<f64>,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub chapters: Vec<Chapter>,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub description: String,
+    /// Languages of subtitles uploaded by the author.
+    #[serde(default, deserialize_with = "language_codes")]
+    pub subtitles: Vec<String>,
+    /// Captions generated by YouTube, including its machine translations.
+    #[serde(default, deserialize_with = "captions")]
+    pub automatic_captions: Vec<Caption>,
+    /// `not_live`, `is_live`, `is_upcoming`, `was_live` or `post_live`.
+    pub live_status: Option<String>,
+    pub language: Option<String>,
+}
+
+impl VideoInfo {
+    /// Streams that are on air or not started yet have no captions to download.
+    pub fn is_live(&self) -> bool {
+        matches!(self.live_status.as_deref(), Some("is_live" | "is_upcoming"))
+    }
+}
+
+/// yt-dlp writes `null` rather than leaving fields out.
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// yt-dlp's map of tracks by language, or the list a cached `VideoInfo` was written with.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Tracks<F, T> {
+    Map(BTreeMap<String, F>),
+    List(Vec<T>),
+}
+
+/// Keeps only the language codes of a subtitle map, the track formats are not needed.
+fn language_codes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let tracks: Option<Tracks<IgnoredAny, String>> = Option::deserialize(deserializer)?;
+    Ok(match tracks {
+        Some(Tracks::Map(tracks)) => tracks.into_keys().collect(),
+        Some(Tracks::List(langs)) => langs,
+        None => vec![],
+    })
+}
+
+/// Translated tracks are served by YouTube with a `tlang` parameter naming the target language.
+fn captions<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Caption>, D::Error> {
+    let tracks: Option<Tracks<Vec<TrackFormat>, Caption>> = Option::deserialize(deserializer)?;
+    Ok(match tracks {
+        Some(Tracks::Map(tracks)) => tracks
+            .into_iter()
+            .map(|(lang, formats)| Caption {
+                translated: formats.iter().any(|format| format.url.contains("tlang=")),
+                lang,
+            })
+            .collect(),
+        Some(Tracks::List(captions)) => captions,
+        None => vec![],
+    })
+}
+
+/// How yt-dlp is invoked, read from the environment.
+pub struct YtDlpConfig {
+    /// Path to the binary, or a name looked up in `PATH`.
+    pub binary: String,
+    /// Netscape cookies file, lets yt-dlp open age-restricted videos as a signed-in user.
+    pub cookies: Option<String>,
+    pub proxy: Option<String>,
+    /// Anything else to pass on every call, e.g. `--limit-rate 1M --sleep-requests 1`.
+    pub extra_args: Vec<String>,
+}
+
+impl YtDlpConfig {
+    pub fn from_env() -> Self {
+        let non_empty = |name: &str| env::var(name).ok().filter(|value| !value.trim().is_empty());
+        Self {
+            binary: non_empty("YT_DLP_PATH").unwrap_or_else(|| "yt-dlp".to_string()),
+            cookies: non_empty("YT_DLP_COOKIES"),
+            proxy: non_empty("YT_DLP_PROXY"),
+            extra_args: non_empty("YT_DLP_ARGS")
+                .map(|args| args.split_whitespace().map(str::to_string).collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Arguments that go before the per-call ones.
+    fn common_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(cookies) = &self.cookies {
+            args.push("--cookies".to_string());
+            args.push(cookies.clone());
+        }
+        if let Some(proxy) = &self.proxy {
+            args.push("--proxy".to_string());
+            args.push(proxy.clone());
+        }
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+}
+
+/// Runs yt-dlp without blocking the runtime, with at most `max_processes` of them alive at once.
+pub struct YtDlp {
+    config: Arc<YtDlpConfig>,
+    slots: Arc<Semaphore>,
+}
+
+impl Clone for YtDlp {
+    fn clone(&self) -> Self {
+        YtDlp {
+            config: Arc::clone(&self.config),
+            slots: Arc::clone(&self.slots),
+        }
+    }
+}
+
+impl YtDlp {
+    pub fn new(config: YtDlpConfig, max_processes: usize) -> Self {
+        Self {
+            config: Arc::new(config),
+            slots: Arc::new(Semaphore::new(max_processes.max(1))),
+        }
+    }
+
+    pub fn binary(&self) -> &str {
+        &self.config.binary
+    }
+
+    /// Checks the binary runs and the cookies file is there, returns the yt-dlp version.
+    /// Meant to fail fast at startup rather than on the first video.
+    pub async fn version(&self) -> Result<String, ExtractError> {
+        if let Some(cookies) = &self.config.cookies {
+            if !Path::new(cookies).is_file() {
+                return Err(ExtractError::Internal(format!("cookies file {} does not exist", cookies)));
+            }
+        }
+        let output = self.run(["--version"], METADATA_TIMEOUT, &CancellationToken::new()).await?;
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Runs yt-dlp to completion. The child is killed when `timeout` passes or `cancel` fires,
+    /// a non-zero exit is classified by what yt-dlp printed to stderr.
+    pub async fn run<I, S>(&self, args: I, timeout: Duration, cancel: &CancellationToken) -> Result<Output, ExtractError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let _slot = tokio::select! {
+            slot = self.slots.acquire() => slot.expect("yt-dlp semaphore closed"),
+            _ = cancel.cancelled() => return Err(ExtractError::Cancelled),
+        };
+        let mut command = Command::new(&self.config.binary);
+        command
+            .args(self.config.common_args())
+            .args(args)
+            .stdin(Stdio::null())
+            // Dropping the output future below kills the child, so it never outlives the job
+            .kill_on_drop(true);
+        let output = tokio::select! {
+            output = tokio::time::timeout(timeout, command.output()) => match output {
+                Ok(output) => output?,
+                Err(_) => {
+                    log::error!("yt-dlp killed after {:?}", timeout);
+                    return Err(ExtractError::Timeout);
+                }
+            },
+            _ = cancel.cancelled() => return Err(ExtractError::Cancelled),
+        };
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            log::error!("yt-dlp exited with {}: {}", output.status, stderr.trim());
+            return Err(ExtractError::from_yt_dlp(&stderr));
+        }
+        Ok(output)
+    }
+
+    pub async fn info(&self, url: &str, cancel: &CancellationToken) -> Result<VideoInfo, ExtractError> {
+        let args = ["--dump-single-json", "--skip-download", "--no-playlist", "--abort-on-error", url];
+        let output = self.run(args, METADATA_TIMEOUT, cancel).await?;
+        serde_json::from_slice(&output.stdout)
+            .map_err(|err| ExtractError::Internal(format!("Unreadable yt-dlp metadata: {}", err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_info_reads_back() {
+        let dumped = r#"{
+            "title": "Reading list", "duration": 600, "chapters": null, "language": "en",
+            "subtitles": {"en": [{"ext": "vtt", "url": "https://example.com/en"}]},
+            "automatic_captions": {
+                "en-orig": [{"url": "https://example.com/api/timedtext?lang=en"}],
+                "de": [{"url": "https://example.com/api/timedtext?lang=en&tlang=de"}]
+            }
+        }"#;
+        let info: VideoInfo = serde_json::from_str(dumped).unwrap();
+        let cached: VideoInfo = serde_json::from_str(&serde_json::to_string(&info).unwrap()).unwrap();
+        assert_eq!(cached.title, "Reading list");
+        assert_eq!(cached.subtitles, vec!["en"]);
+        let captions: Vec<(&str, bool)> = cached.automatic_captions.iter().map(|caption| (caption.lang.as_str(), caption.translated)).collect();
+        assert_eq!(captions, vec![("de", true), ("en-orig", false)]);
+    }
+}
@@ -0,0 +1,81 @@
+//! Property tests for transcript chunking on mixed Latin, Cyrillic and emoji input.
+
+use std::sync::OnceLock;
+
+use proptest::prelude::*;
+
+#[path = "../src/chunks.rs"]
+mod chunks;
+
+use chunks::Chunker;
+
+fn chunker(chunk_tokens: usize, overlap_tokens: usize) -> Chunker {
+    // Loading the tokenizer takes longer than a whole test case
+    static BASE: OnceLock<Chunker> = OnceLock::new();
+    let mut chunker = BASE.get_or_init(|| Chunker::from_env("gpt-4o-mini").unwrap()).clone();
+    chunker.chunk_tokens = chunk_tokens;
+    chunker.overlap_tokens = overlap_tokens.min(chunk_tokens / 2);
+    chunker
+}
+
+/// Latin, Cyrillic, emoji including joined sequences, numbers and punctuation, with words
+/// long enough to need cutting between characters.
+const TRANSCRIPT: &str = "([a-z]{1,12}|[а-яёА-Я]{1,12}|[😀-🙏🎉👍]{1,8}|👨‍👩‍👧|[0-9]{1,4}|[,.!?«»]|[а-я😀-🙏]{30,200}| {1,3}|\n)";
+
+fn transcript() -> impl Strategy<Value = String> {
+    prop::string::string_regex(&format!("{}{{0,400}}", TRANSCRIPT)).unwrap()
+}
+
+/// Chapters of a transcript, mostly shorter than a chunk.
+fn chapters() -> impl Strategy<Value = Vec<String>> {
+    prop::collection::vec(prop::string::string_regex(&format!("{}{{1,60}}", TRANSCRIPT)).unwrap(), 0..12)
+}
+
+fn squeeze(text: &str) -> String {
+    text.chars().filter(|ch| !ch.is_whitespace()).collect()
+}
+
+proptest! {
+    // Tokenizing is slow in debug builds
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn chunks_fit_the_limit(text in transcript(), chunk_tokens in 8usize..200, overlap_tokens in 0usize..100) {
+        let chunker = chunker(chunk_tokens, overlap_tokens);
+        for chunk in chunker.split(&text) {
+            prop_assert!(chunker.count(&chunk) <= chunk_tokens, "{} tokens in {:?}", chunker.count(&chunk), chunk);
+        }
+    }
+
+    #[test]
+    fn chunks_keep_the_whole_text_in_order(text in transcript(), chunk_tokens in 8usize..200) {
+        let chunks = chunker(chunk_tokens, 0).split(&text);
+        prop_assert_eq!(squeeze(&chunks.concat()), squeeze(&text));
+    }
+
+    #[test]
+    fn overlapping_chunks_are_runs_of_the_text(text in transcript(), chunk_tokens in 8usize..200, overlap_tokens in 1usize..100) {
+        let whole = squeeze(&text);
+        let chunks = chunker(chunk_tokens, overlap_tokens).split(&text);
+        for chunk in &chunks {
+            prop_assert!(whole.contains(&squeeze(chunk)), "{:?} is not a run of the text", chunk);
+        }
+        if let Some(last) = chunks.last() {
+            prop_assert!(whole.ends_with(&squeeze(last)));
+        }
+    }
+
+    #[test]
+    fn chapters_are_cut_between_unless_too_long(sections in chapters(), chunk_tokens in 8usize..200, overlap_tokens in 0usize..100) {
+        let chunker = chunker(chunk_tokens, overlap_tokens);
+        let squeezed: Vec<String> = sections.iter().map(|section| squeeze(section)).collect();
+        for (chunk, held) in chunker.split_sections(&sections) {
+            prop_assert!(chunker.count(&chunk) <= chunk_tokens);
+            let chunk = squeeze(&chunk);
+            // Either the whole consecutive chapters it names or a part of a single one
+            let whole = squeezed[held.clone()].concat() == chunk;
+            let part = held.len() == 1 && squeezed[held.start].contains(&chunk);
+            prop_assert!(whole || part, "{:?} does not hold chapters {:?}", chunk, held);
+        }
+    }
+}